@@ -3,6 +3,7 @@
 use crate::ui::{Command, UIContent, Ui};
 
 use rppal::gpio::Gpio;
+use std::cell::RefCell;
 use std::error::Error;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
@@ -10,9 +11,11 @@ use std::thread;
 use std::time::Duration;
 
 pub mod button;
+pub mod led;
 pub mod rotary;
 
 use button::{Button, Momentary};
+use led::Led;
 use rotary::{Encoder, Rotary};
 
 pub struct HardUi {
@@ -20,6 +23,8 @@ pub struct HardUi {
     encoders_rx: Receiver<(usize, i32)>,
     /// the buttons
     buttons_rx: Receiver<usize>,
+    /// lights up on the first step of every beat while its playing
+    beat: RefCell<Led>,
 }
 
 /*
@@ -30,6 +35,16 @@ is remember this:
 
 0: track_select
 1: tempo
+2: swing, its the tempo encoder with shift held
+
+buttons are the same deal, just the index:
+
+0: place_beat
+1: ratchet, its place_beat with shift held
+2: preview, plays the track
+3: loud preview, its preview with shift held
+4: record, turns record mode on and off. preview records while its on
+5: overdub or replace, its record with shift held
 */
 
 impl HardUi {
@@ -43,28 +58,46 @@ impl HardUi {
             let track_select = Encoder::new(17, 18, &gpio).expect("pins already in use");
             let tempo = Encoder::new(22, 23, &gpio).expect("pins already in use");
             let place_beat = Button::new(3, &gpio).expect("pin already in use");
+            let preview = Button::new(24, &gpio).expect("pin already in use");
+            let record = Button::new(25, &gpio).expect("pin already in use");
+            let shift = Button::new(27, &gpio).expect("pin already in use");
             poll(
                 encoders_tx,
                 buttons_tx,
                 vec![track_select, tempo],
-                vec![place_beat],
+                vec![place_beat, preview, record],
+                shift,
             );
         });
 
+        let beat = Led::new(16, &Gpio::new()?)?;
+
         Ok(HardUi {
             encoders_rx,
             buttons_rx,
+            beat: RefCell::new(beat),
         })
     }
 }
 
 impl Ui for HardUi {
-    fn update(&self, content: UIContent) {}
+    fn update(&self, content: UIContent) {
+        // the playhead, blinks along with the beat and stays off while stopped
+        let on_beat = content
+            .playhead
+            .is_some_and(|at| at.position % content.divisions.max(1) as u64 == 0);
+        self.beat.borrow_mut().set(on_beat);
+    }
 
     fn get_command(&self) -> Command {
         let clicked_buttons = match self.buttons_rx.try_recv() {
             Ok(b) => match b {
                 0 => ' ',
+                1 => 't',
+                2 => '2',
+                3 => '3',
+                4 => 'w',
+                5 => 'D',
                 _ => '0',
             },
             Err(_) => '0',
@@ -90,6 +123,13 @@ impl Ui for HardUi {
                         '-'
                     }
                 }
+                2 => {
+                    if m.1 > 0 {
+                        '>'
+                    } else {
+                        '<'
+                    }
+                }
                 _ => '0',
             },
             Err(_) => '0',
@@ -104,24 +144,30 @@ impl Ui for HardUi {
 /// button_tx: sends the index of the button what got pressed
 /// encoders: vec of encoders
 /// buttons: vec of buttons
+/// shift: held down it turns the tempo encoder into the swing one, place beat into
+/// ratchet, preview into loud preview and record into overdub or replace
 pub fn poll(
     encoder_tx: Sender<(usize, i32)>,
     button_tx: Sender<usize>,
     mut encoders: Vec<Encoder>,
     mut buttons: Vec<Button>,
+    mut shift: Button,
 ) {
     loop {
+        let shifted = shift.held();
         for i in 0..encoders.len() {
             let now = encoders[i].update();
             if now != 0 {
-                encoder_tx.send((i, now));
+                let index = if shifted && i == 1 { 2 } else { i };
+                encoder_tx.send((index, now));
             }
         }
 
         for i in 0..buttons.len() {
             let now = buttons[i].clicked();
             if now {
-                button_tx.send(i);
+                // every button has a shifted one right after it
+                button_tx.send(i * 2 + shifted as usize);
             }
         }
         // TODO: adaptive wait? so it always takes ~1ms to check again?
@@ -21,6 +21,9 @@ pub trait Momentary {
     /// did the button get pressed, only reports the transition from off to on
     /// returns true if the button went from off to on, false otherwise
     fn clicked(&mut self) -> bool;
+
+    /// is the button down right now, for modifiers that get held while turning something
+    fn held(&mut self) -> bool;
 }
 
 impl Momentary for Button {
@@ -28,4 +31,8 @@ impl Momentary for Button {
         self.state = (self.state << 1) | self.bpin.is_high() as u16 | 0xfe00;
         self.state == 0xff00
     }
+
+    fn held(&mut self) -> bool {
+        self.bpin.is_low()
+    }
 }
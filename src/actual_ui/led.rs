@@ -0,0 +1,26 @@
+use rppal::gpio::{Gpio, OutputPin};
+use std::error::Error;
+
+/// an led, active HIGH
+pub struct Led {
+    lpin: OutputPin,
+}
+
+impl Led {
+    /// takes a BCM pin number and returns a new Led thats off, fails if the
+    /// given pin is already being used
+    pub fn new(pin: u8, gpio: &Gpio) -> Result<Led, Box<dyn Error>> {
+        let mut lpin = gpio.get(pin)?.into_output();
+        lpin.set_low();
+        Ok(Led { lpin })
+    }
+
+    /// turn it on or off
+    pub fn set(&mut self, on: bool) {
+        if on {
+            self.lpin.set_high();
+        } else {
+            self.lpin.set_low();
+        }
+    }
+}
@@ -0,0 +1,244 @@
+/**
+Effects, on every track and on the two send buses.
+
+Every track has a filter, a distortion and a delay as inserts, in that order before its
+volume and pan. Then it can send some of itself to the reverb bus and the delay bus, which
+every track shares. All the knobs go in whole numbers so they save and step nicely, mostly
+percent. Delay times are in steps so they keep time with the tempo.
+
+The inserts are out of the way until they get turned: the filter is wide open, the
+distortion has no drive and the delay is all dry.
+*/
+use std::fmt;
+use std::str::FromStr;
+
+/// the lowest and highest the filter goes, in Hz
+pub const CUTOFF_RANGE: (f64, f64) = (20.0, 20_000.0);
+
+/// the longest a delay can be in seconds, steps that would be longer get cut to it
+pub const MAX_DELAY_SECONDS: f64 = 4.0;
+
+/// A knob on one of the effects
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Knob {
+    /// filter cutoff, percent of the way up from `CUTOFF_RANGE`'s low end
+    Cutoff,
+    /// filter resonance, percent
+    Resonance,
+    /// distortion drive in dB
+    Drive,
+    /// insert delay time in steps
+    Delay,
+    /// insert delay feedback, percent
+    Feedback,
+    /// how much of the insert delay is heard, percent
+    Wet,
+    /// how much of the track goes to the reverb bus, percent
+    ReverbSend,
+    /// how much of the track goes to the delay bus, percent
+    DelaySend,
+    /// how long the reverb bus rings, percent
+    ReverbSize,
+    /// how much the reverb bus dulls the highs, percent
+    ReverbDamping,
+    /// delay bus time in steps
+    BusDelay,
+    /// delay bus feedback, percent
+    BusFeedback,
+}
+
+const KNOBS: usize = 12;
+
+impl Knob {
+    /// every knob, the track ones then the bus ones
+    pub fn all() -> [Knob; KNOBS] {
+        [
+            Knob::Cutoff,
+            Knob::Resonance,
+            Knob::Drive,
+            Knob::Delay,
+            Knob::Feedback,
+            Knob::Wet,
+            Knob::ReverbSend,
+            Knob::DelaySend,
+            Knob::ReverbSize,
+            Knob::ReverbDamping,
+            Knob::BusDelay,
+            Knob::BusFeedback,
+        ]
+    }
+
+    /// its on the send buses, shared by every track
+    pub fn is_bus(&self) -> bool {
+        matches!(
+            self,
+            Knob::ReverbSize | Knob::ReverbDamping | Knob::BusDelay | Knob::BusFeedback
+        )
+    }
+
+    /// the lowest and highest it goes
+    pub fn range(&self) -> (u8, u8) {
+        match self {
+            Knob::Drive => (0, 48),
+            Knob::Delay | Knob::BusDelay => (1, 16),
+            Knob::Feedback | Knob::BusFeedback | Knob::ReverbSize => (0, 95),
+            _ => (0, 100),
+        }
+    }
+
+    /// how far one press of + or - turns it
+    pub fn increment(&self) -> u8 {
+        match self {
+            Knob::Drive => 3,
+            Knob::Delay | Knob::BusDelay => 1,
+            Knob::Wet | Knob::ReverbSend | Knob::DelaySend => 10,
+            _ => 5,
+        }
+    }
+
+    /// where it starts
+    pub fn default_value(&self) -> u8 {
+        match self {
+            Knob::Cutoff => 100,
+            Knob::Delay | Knob::BusDelay => 3,
+            Knob::Feedback => 30,
+            Knob::ReverbSize => 80,
+            Knob::ReverbDamping => 10,
+            Knob::BusFeedback => 40,
+            _ => 0,
+        }
+    }
+
+    fn index(&self) -> usize {
+        Knob::all().iter().position(|k| k == self).unwrap_or(0)
+    }
+}
+
+/// how knobs are written down, eg: in the pattern file
+impl fmt::Display for Knob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Knob::Cutoff => "cutoff",
+            Knob::Resonance => "res",
+            Knob::Drive => "drive",
+            Knob::Delay => "delay",
+            Knob::Feedback => "feedback",
+            Knob::Wet => "wet",
+            Knob::ReverbSend => "reverb-send",
+            Knob::DelaySend => "delay-send",
+            Knob::ReverbSize => "reverb-size",
+            Knob::ReverbDamping => "reverb-damping",
+            Knob::BusDelay => "bus-delay",
+            Knob::BusFeedback => "bus-feedback",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Knob {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Knob::all()
+            .into_iter()
+            .find(|k| k.to_string() == s)
+            .ok_or("no such effect knob")
+    }
+}
+
+/// Where the knobs are at, for a track or the buses
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Effects {
+    values: [u8; KNOBS],
+}
+
+impl Effects {
+    pub fn get(&self, knob: Knob) -> u8 {
+        self.values[knob.index()]
+    }
+
+    /// turn a knob to a value
+    ///
+    /// returns an error if its outside the knob's range
+    pub fn set(&mut self, knob: Knob, value: u8) -> Result<(), &'static str> {
+        let (low, high) = knob.range();
+        if !(low..=high).contains(&value) {
+            return Err("effect knob out of range");
+        }
+        self.values[knob.index()] = value;
+        Ok(())
+    }
+
+    /// turn a knob up or down by its increment, it stops at the ends of its range
+    pub fn turn(&mut self, knob: Knob, up: bool) {
+        let (low, high) = knob.range();
+        let value = self.get(knob);
+        self.values[knob.index()] = if up {
+            value.saturating_add(knob.increment()).min(high)
+        } else {
+            value.saturating_sub(knob.increment()).max(low)
+        };
+    }
+
+    /// the knobs that have been turned, of the track ones or the bus ones
+    pub fn changed(&self, bus: bool) -> Vec<(Knob, u8)> {
+        Knob::all()
+            .into_iter()
+            .filter(|k| k.is_bus() == bus && self.get(*k) != k.default_value())
+            .map(|k| (k, self.get(k)))
+            .collect()
+    }
+}
+
+impl Default for Effects {
+    fn default() -> Self {
+        Effects {
+            values: Knob::all().map(|k| k.default_value()),
+        }
+    }
+}
+
+/// filter cutoff in Hz for where the cutoff knob is at
+pub fn cutoff_hz(value: u8) -> f64 {
+    let (low, high) = CUTOFF_RANGE;
+    low * (high / low).powf(fraction(value))
+}
+
+/// a percent knob as 0 to 1
+pub fn fraction(value: u8) -> f64 {
+    value as f64 / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cutoff_hz, Effects, Knob, CUTOFF_RANGE};
+
+    #[test]
+    fn knobs() {
+        let mut fx = Effects::default();
+        assert!(fx.changed(false).is_empty());
+        assert_eq!(cutoff_hz(fx.get(Knob::Cutoff)), CUTOFF_RANGE.1);
+        assert!((cutoff_hz(0) - CUTOFF_RANGE.0).abs() < 1e-9);
+
+        fx.set(Knob::Cutoff, 0).unwrap();
+        assert!(fx.set(Knob::Drive, 49).is_err());
+        assert!(fx.set(Knob::Delay, 0).is_err());
+
+        fx.turn(Knob::Drive, true);
+        fx.turn(Knob::Delay, false);
+        fx.turn(Knob::Delay, false);
+        fx.turn(Knob::Delay, false);
+        assert_eq!(fx.get(Knob::Drive), 3);
+        assert_eq!(fx.get(Knob::Delay), 1);
+        assert_eq!(
+            fx.changed(false),
+            vec![(Knob::Cutoff, 0), (Knob::Drive, 3), (Knob::Delay, 1)]
+        );
+        fx.turn(Knob::BusDelay, true);
+        assert_eq!(fx.changed(true), vec![(Knob::BusDelay, 4)]);
+
+        for knob in Knob::all() {
+            assert_eq!(knob.to_string().parse(), Ok(knob));
+        }
+    }
+}
@@ -1,12 +1,20 @@
 use std::error::Error;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+pub mod effects;
+pub mod midi;
+pub mod mixer;
+pub mod pattern;
 pub mod playback;
+pub mod render;
 pub mod samples;
 pub mod sequencer;
+pub mod song;
 pub mod ui;
+pub mod velocity;
 
 #[cfg(not(pi))]
 pub mod test_ui;
@@ -14,109 +22,611 @@ pub mod test_ui;
 #[cfg(pi)]
 pub mod actual_ui;
 
-use kira::sound::static_sound::StaticSoundData;
-use playback::{Controls, PlayBack, Player};
-use sequencer::{AccentLevel, SampleSequence, Sequence};
+use effects::{Effects, Knob};
+use mixer::ChannelStrip;
+use pattern::Pattern;
+use playback::{
+    Controls, PlayBack, Player, Playhead, Recorded, Recorder, Status, CHOKE_GROUPS, MAX_SWING,
+};
+use samples::Library;
+use sequencer::{
+    AccentLevel, Condition, Lock, SampleSequence, Sequence, MAX_NUDGE, MAX_RATCHET, MAX_VELOCITY,
+};
+use song::{Song, SongEntry};
 use ui::{UIContent, Ui};
+use velocity::{AccentGains, VelocityCurve};
+
+/// how much `v` and `V` change a step's velocity by
+static VELOCITY_STEP: u8 = 8;
+
+/// how much the pan keys move a track
+static PAN_STEP: i8 = 10;
+
+/// how much `^` takes off the quantize strength each time
+static QUANTIZE_STEP: u8 = 25;
+
+/// how long the library has to stay the same after it changes before the kit gets loaded
+/// again
+static RELOAD_DELAY: Duration = Duration::from_millis(250);
+
+/// the track each one was before a kit change, None for new ones, and warnings about the
+/// samples that wouldnt load
+type KitChange = (Vec<Option<usize>>, Vec<String>);
 
 /// app? state :trollface:
 struct State {
     pub tempo: u32,
+    pub divisions: u32,
     pub muted: bool,
+    /// fill is on, for the fill trig conditions
+    pub fill: bool,
+    /// the lock being edited in step edit mode, None outside of it
+    pub step_edit: Option<Lock>,
+    /// where the dice start in renders, kept so saving doesnt lose it
+    pub seed: u64,
+    /// how loud each velocity is
+    pub curve: VelocityCurve,
+    /// extra gain for each accent level
+    pub accents: AccentGains,
+    /// per track accent gains, None for the global ones
+    pub track_accents: Vec<Option<AccentGains>>,
+    /// the accent keys change the selected track's gains instead of the global ones
+    pub track_accent_edit: bool,
+    /// every track's channel strip
+    pub mix: Vec<ChannelStrip>,
+    /// every track's effects
+    pub effects: Vec<Effects>,
+    /// the send buses' effects
+    pub bus_effects: Effects,
+    /// the knob being turned in effect edit mode, None outside of it
+    pub fx_edit: Option<Knob>,
+    /// every track's choke group, None for tracks that dont choke
+    pub choke: Vec<Option<u8>>,
+    /// percent of a step every second step is late
+    pub swing: u32,
+    /// per track swing, None for the global one
+    pub track_swing: Vec<Option<u32>>,
     pub selected_track: usize,
     pub step: usize,
-    pub sequence: SampleSequence,
-    pub samples: Vec<StaticSoundData>,
+    /// every pattern, by slot
+    pub bank: Vec<SampleSequence>,
+    /// the slot being edited
+    pub edit: usize,
+    /// the slot to play, takes over when the playing one wraps around
+    pub cued: usize,
+    /// the arrangement for song mode
+    pub song: Song,
+    /// play the song instead of the cued pattern
+    pub song_mode: bool,
+    /// the song entry being edited
+    pub song_entry: usize,
+    /// the kit the samples come out of
+    pub kit: String,
+    /// all of the kit's samples, in the order it loads them
+    pub kit_samples: samples::ActiveSamples,
+    /// the sample each track plays, in track order
+    pub samples: samples::ActiveSamples,
+    /// which sample in the bank each track plays
+    pub sample_map: Vec<usize>,
+    /// the preview keys record what they play into the pattern thats playing
+    pub record: bool,
+    /// quantize strength and overdub or replace
+    pub recorder: Recorder,
+    /// where playback is, None while its stopped
+    pub playhead: Option<Playhead>,
+    /// status line, eg: save and load errors
+    pub message: String,
+}
+
+/// what to start playing with
+pub enum Session {
+    /// a fresh empty pattern, saved to path
+    New {
+        steps: usize,
+        divisions: u32,
+        path: PathBuf,
+    },
+    /// the pattern in an existing file, saved back to it
+    Open { path: PathBuf },
+}
+
+/// Bounce a pattern file to a wav file
+///
+/// pattern: the pattern file to render
+///
+/// out: the wav file to write
+///
+/// loops: how many times to play through the pattern
+///
+/// library: where the pattern's kit is
+///
+/// returns a warning for every sample that wouldnt load
+pub fn render(
+    pattern: &Path,
+    out: &Path,
+    loops: u32,
+    library: &Library,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let (pattern, samples, warnings) = load_for_render(pattern, library)?;
+    let frames = render::render(&pattern, samples, loops, render::SAMPLE_RATE)?;
+    render::write_wav(out, &frames, render::SAMPLE_RATE)?;
+    Ok(warnings)
+}
+
+/// Bounce the song in a pattern file to a wav file, start to end
+///
+/// pattern: the pattern file to render
+///
+/// out: the wav file to write
+///
+/// library: where the pattern's kit is
+///
+/// returns a warning for every sample that wouldnt load
+pub fn render_song(
+    pattern: &Path,
+    out: &Path,
+    library: &Library,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let (pattern, samples, warnings) = load_for_render(pattern, library)?;
+    let frames = render::render_song(&pattern, samples, render::SAMPLE_RATE)?;
+    render::write_wav(out, &frames, render::SAMPLE_RATE)?;
+    Ok(warnings)
+}
+
+/// a pattern file, the samples its tracks play and warnings about the ones that didnt load
+fn load_for_render(
+    path: &Path,
+    library: &Library,
+) -> Result<(Pattern, samples::ActiveSamples, Vec<String>), Box<dyn Error>> {
+    let pattern =
+        Pattern::load(path).map_err(|e| format!("cant open {}: {}", path.display(), e))?;
+    let (_, bank, warnings) = load_kit(&pattern, library)?;
+    validate(&pattern, &bank, &warnings)?;
+    let samples = pattern.track_samples(&bank);
+    Ok((pattern, samples, warnings))
+}
+
+/// the kit a pattern plays, the library's first one if it doesnt say, its samples and
+/// warnings about the ones that didnt load
+fn load_kit(
+    pattern: &Pattern,
+    library: &Library,
+) -> Result<(String, samples::ActiveSamples, Vec<String>), Box<dyn Error>> {
+    let kit = match &pattern.kit {
+        Some(kit) => kit.clone(),
+        None => library.first_kit()?,
+    };
+    let (bank, warnings) = library.load(&kit)?;
+    Ok((kit, bank, warnings))
+}
+
+/// check a pattern fits its kit, a sample that didnt load is likely why it doesnt so the
+/// error says which
+fn validate(
+    pattern: &Pattern,
+    bank: &samples::ActiveSamples,
+    warnings: &[String],
+) -> Result<(), Box<dyn Error>> {
+    pattern.validate(bank.len()).map_err(|e| {
+        let mut e = e.to_string();
+        for warning in warnings {
+            e.push_str(&format!("\n{}", warning));
+        }
+        e.into()
+    })
+}
+
+/// Export a pattern file as a midi file
+///
+/// pattern: the pattern file to export
+///
+/// out: the midi file to write
+///
+/// drum_map: file with the notes each track plays, General MIDI drums if none
+pub fn export_midi(
+    pattern: &Path,
+    out: &Path,
+    drum_map: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let pattern =
+        Pattern::load(pattern).map_err(|e| format!("cant open {}: {}", pattern.display(), e))?;
+    let map = match drum_map {
+        Some(path) => {
+            midi::DrumMap::load(path).map_err(|e| format!("cant open {}: {}", path.display(), e))?
+        }
+        None => midi::DrumMap::default(),
+    };
+    midi::export(&pattern, &map, out)
+}
+
+/// Turn a midi file into a pattern file, one track per loaded sample
+///
+/// midi: the midi file to import
+///
+/// out: the pattern file to write
+///
+/// steps, divisions: the grid notes get quantized to
+///
+/// drum_map: file with the notes each track plays, General MIDI drums if none
+///
+/// library: the pattern gets its first kit
+///
+/// returns the notes that got moved or dropped on the way
+pub fn import_midi(
+    midi: &Path,
+    out: &Path,
+    steps: usize,
+    divisions: u32,
+    drum_map: Option<&Path>,
+    library: &Library,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let kit = library.first_kit()?;
+    let (bank, warnings) = library.load(&kit)?;
+    let map = match drum_map {
+        Some(path) => {
+            midi::DrumMap::load(path).map_err(|e| format!("cant open {}: {}", path.display(), e))?
+        }
+        None => midi::DrumMap::default(),
+    };
+    let (mut pattern, report) = midi::import(midi, &map, bank.len(), steps, divisions)
+        .map_err(|e| format!("cant import {}: {}", midi.display(), e))?;
+    pattern.kit = Some(kit);
+    pattern.choke = bank.iter().map(|s| s.choke).collect();
+    pattern.save(out)?;
+    Ok(warnings.into_iter().chain(report).collect())
 }
 
 /// The program control loop
 ///
 /// display: handle to UI
-pub fn play(display: &impl Ui, steps: usize, divisions: u32) -> Result<(), Box<dyn Error>> {
-    let samples = samples::load()?;
+///
+/// session: the pattern to start with and where it gets saved
+///
+/// library: where the kits are
+pub fn play(display: &impl Ui, session: Session, library: &Library) -> Result<(), Box<dyn Error>> {
+    let (pattern, path, kit, bank, warnings) = match session {
+        Session::New {
+            steps,
+            divisions,
+            path,
+        } => {
+            let kit = library.first_kit()?;
+            let (bank, warnings) = library.load(&kit)?;
+            let mut pattern = Pattern::new(bank.len(), steps, divisions, 180);
+            pattern.kit = Some(kit.clone());
+            pattern.choke = bank.iter().map(|s| s.choke).collect();
+            (pattern, path, kit, bank, warnings)
+        }
+        Session::Open { path } => {
+            let pattern =
+                Pattern::load(&path).map_err(|e| format!("cant open {}: {}", path.display(), e))?;
+            let (kit, bank, warnings) = load_kit(&pattern, library)?;
+            validate(&pattern, &bank, &warnings)?;
+            (pattern, path, kit, bank, warnings)
+        }
+    };
 
+    let samples = pattern.track_samples(&bank);
     let mut state = State {
         muted: false,
-        tempo: 180,
+        fill: false,
+        step_edit: None,
+        seed: pattern.seed,
+        curve: pattern.curve,
+        accents: pattern.accents,
+        track_accents: pattern.track_accents,
+        track_accent_edit: false,
+        mix: pattern.mix,
+        effects: pattern.effects,
+        bus_effects: pattern.bus_effects,
+        fx_edit: None,
+        choke: pattern.choke,
+        tempo: pattern.tempo,
+        divisions: pattern.divisions,
+        swing: pattern.swing,
+        track_swing: pattern.track_swing,
         selected_track: 0,
         step: 0,
-        sequence: SampleSequence::new(samples.len(), steps),
+        edit: pattern.play,
+        cued: pattern.play,
+        bank: pattern.bank,
+        song: pattern.song,
+        song_mode: false,
+        song_entry: 0,
+        kit,
+        kit_samples: bank,
         samples,
+        sample_map: pattern.samples,
+        record: false,
+        recorder: Recorder::default(),
+        playhead: None,
+        message: warnings.join(" "),
+    };
+
+    // changes to the samples get picked up while it plays
+    let watcher = match library.watch() {
+        Ok(w) => Some(w),
+        Err(e) => {
+            state.message = format!("cant watch {}: {}", library.dir.display(), e);
+            None
+        }
     };
+    let mut reload_at = None;
 
     let (seq_tx, seq_rx) = mpsc::channel();
     let (control_tx, control_rx) = mpsc::channel();
+    let (sample_tx, sample_rx) = mpsc::channel();
+    let (preview_tx, preview_rx) = mpsc::channel();
+    let (status_tx, status_rx) = mpsc::channel();
 
-    let mut player = PlayBack::setup(state.samples)?;
+    let mut player = PlayBack::setup(state.samples.clone())?;
+    let divisions = state.divisions;
 
     let _playback_handle = thread::spawn(move || {
         // FIXME: unwrap lmao
         player
-            .begin_playback(seq_rx, control_rx, divisions)
+            .begin_playback(
+                seq_rx, control_rx, sample_rx, preview_rx, status_tx, divisions,
+            )
             .unwrap();
     });
 
-    seq_tx.send(state.sequence.get_sequence())?;
+    control_tx.send(state.controls())?;
+    for (slot, sequence) in state.bank.iter().enumerate() {
+        seq_tx.send((slot, sequence.get_sequence()))?;
+    }
 
     loop {
         thread::sleep(Duration::from_millis(10));
         let command = display.get_command();
 
         let mut send_control = false;
+        let mut send_bank = false;
+
+        // editors save in bursts, wait for them to finish before loading the kit again
+        let kit_dir = library.kit_dir(&state.kit);
+        if watcher.as_ref().is_some_and(|w| w.changed(&kit_dir)) {
+            reload_at = Some(Instant::now() + RELOAD_DELAY);
+        }
+        let reload = reload_at.is_some_and(|at| Instant::now() >= at);
+        if reload {
+            reload_at = None;
+            state.message = match state.reload_kit(library) {
+                Ok((from, warnings)) => {
+                    sample_tx.send((state.samples.clone(), from))?;
+                    send_control = true;
+                    send_bank = true;
+                    format!("reloaded kit {} {}", state.kit, warnings.join(" "))
+                }
+                Err(e) => format!("cant reload kit {}: {}", state.kit, e),
+            };
+        }
+
+        // where playback is, and hits played in while recording. those go in whatever
+        // pattern is playing
+        let mut heard = false;
+        while let Ok(status) = status_rx.try_recv() {
+            match status {
+                Status::Playhead(at) => {
+                    state.playhead = at;
+                    heard = true;
+                }
+                // the pattern can have changed under it, eg: a shorter track
+                Status::Recorded(hit) => {
+                    if state.record_hit(hit).is_ok() {
+                        seq_tx.send((hit.slot, state.bank[hit.slot].get_sequence()))?;
+                        heard = true;
+                    }
+                }
+            }
+        }
+
+        let track = state.selected_track;
+        let sequence = &mut state.bank[state.edit];
         match command {
-            'k' => {
-                if state.selected_track >= 1 {
-                    state.selected_track -= 1;
+            // step edit mode, j / k pick a lock and + / - change it on the current step
+            'L' => {
+                state.fx_edit = None;
+                state.step_edit = match state.step_edit {
+                    Some(_) => None,
+                    None => Some(Lock::Pitch),
                 }
             }
-            'j' => {
-                if state.selected_track < state.sequence.num_tracks() - 1 {
-                    state.selected_track += 1;
+            'j' | 'k' if state.step_edit.is_some() => {
+                let all = Lock::all();
+                let at = all.iter().position(|&l| Some(l) == state.step_edit);
+                let at = at.unwrap_or(0);
+                state.step_edit = Some(if command == 'j' {
+                    all[(at + 1) % all.len()]
+                } else {
+                    all[(at + all.len() - 1) % all.len()]
+                });
+            }
+            '+' | '-' | 'c' if state.step_edit.is_some() => {
+                if let (Some(lock), Some(mut step)) =
+                    (state.step_edit, sequence.get_step(track, state.step))
+                {
+                    let (low, high) = lock.range();
+                    let value = step.locks.get(lock).unwrap_or(0);
+                    let value = match command {
+                        '+' => Some(value.saturating_add(lock.increment()).min(high)),
+                        '-' => Some(value.saturating_sub(lock.increment()).max(low)),
+                        _ => None,
+                    };
+                    step.locks.set(lock, value)?;
+                    sequence.put_step(track, state.step, step)?;
+                    send_control = true;
                 }
             }
-            'l' => {
-                if state.step < state.sequence.steps() {
-                    state.step += 1;
+            // effect edit mode, j / k pick a knob and + / - turn it on the current track or
+            // the buses
+            'F' => {
+                state.step_edit = None;
+                state.fx_edit = match state.fx_edit {
+                    Some(_) => None,
+                    None => Some(Knob::Cutoff),
                 }
             }
-            'h' => {
-                if state.step > 0 {
-                    state.step -= 1;
+            'j' | 'k' if state.fx_edit.is_some() => {
+                let all = Knob::all();
+                let at = all.iter().position(|&k| Some(k) == state.fx_edit);
+                let at = at.unwrap_or(0);
+                state.fx_edit = Some(if command == 'j' {
+                    all[(at + 1) % all.len()]
+                } else {
+                    all[(at + all.len() - 1) % all.len()]
+                });
+            }
+            '+' | '-' | 'c' if state.fx_edit.is_some() => {
+                if let Some(knob) = state.fx_edit {
+                    let fx = if knob.is_bus() {
+                        &mut state.bus_effects
+                    } else {
+                        &mut state.effects[track]
+                    };
+                    match command {
+                        'c' => fx.set(knob, knob.default_value())?,
+                        _ => fx.turn(knob, command == '+'),
+                    }
+                    send_control = true;
                 }
             }
+            'k' if state.selected_track >= 1 => state.selected_track -= 1,
+            'j' if state.selected_track < sequence.num_tracks() - 1 => state.selected_track += 1,
+            'l' if state.step + 1 < sequence.track_steps(track) => state.step += 1,
+            'h' if state.step > 0 => state.step -= 1,
 
             'm' => {
                 state.muted = !state.muted;
                 send_control = true;
             }
+            'f' => {
+                state.fill = !state.fill;
+                send_control = true;
+            }
             // hits
             ' ' => {
-                state
-                    .sequence
-                    .set_step(state.selected_track, state.step, AccentLevel::Regular)?;
+                sequence.set_step(track, state.step, AccentLevel::Regular)?;
                 send_control = true;
             }
             'd' => {
-                state
-                    .sequence
-                    .set_step(state.selected_track, state.step, AccentLevel::Loud)?;
+                sequence.set_step(track, state.step, AccentLevel::Loud)?;
                 send_control = true;
             }
             's' => {
-                state
-                    .sequence
-                    .set_step(state.selected_track, state.step, AccentLevel::Soft)?;
+                sequence.set_step(track, state.step, AccentLevel::Soft)?;
                 send_control = true;
             }
+            // hear the track without putting it in the pattern, unless its recording
+            '1' => preview_tx.send((track, AccentLevel::Soft, state.record))?,
+            '2' => preview_tx.send((track, AccentLevel::Regular, state.record))?,
+            '3' => preview_tx.send((track, AccentLevel::Loud, state.record))?,
+            // record mode
+            'w' => {
+                state.record = !state.record;
+                state.recorder.restart();
+            }
+            'D' => {
+                state.recorder.overdub = !state.recorder.overdub;
+                state.recorder.restart();
+            }
+            '^' => {
+                state.recorder.quantize = match state.recorder.quantize {
+                    0 => 100,
+                    q => q.saturating_sub(QUANTIZE_STEP),
+                }
+            }
+            // velocity, in between the presets
+            'v' | 'V' => {
+                // an empty step stays empty, theres no hit to make louder
+                if let Some(mut step) = sequence
+                    .get_step(track, state.step)
+                    .filter(|s| s.velocity > 0)
+                {
+                    step.velocity = if command == 'v' {
+                        step.velocity.saturating_sub(VELOCITY_STEP).max(1)
+                    } else {
+                        (step.velocity + VELOCITY_STEP).min(MAX_VELOCITY)
+                    };
+                    sequence.put_step(track, state.step, step)?;
+                    send_control = true;
+                }
+            }
             // clearing
             'c' => {
-                state
-                    .sequence
-                    .set_step(state.selected_track, state.step, AccentLevel::Silent)?;
+                sequence.set_step(track, state.step, AccentLevel::Silent)?;
                 send_control = true;
             }
             'C' => {
-                state.sequence.clear_track(state.selected_track);
+                sequence.clear_track(track);
+                send_control = true;
+            }
+            // chance and condition of the step
+            'q' | 'Q' => {
+                if let Some(mut step) = sequence.get_step(track, state.step) {
+                    step.probability = if command == 'q' {
+                        step.probability.saturating_sub(10)
+                    } else {
+                        (step.probability + 10).min(100)
+                    };
+                    sequence.put_step(track, state.step, step)?;
+                    send_control = true;
+                }
+            }
+            'i' | 'I' => {
+                if let Some(mut step) = sequence.get_step(track, state.step) {
+                    let all = Condition::all();
+                    let at = all.iter().position(|&c| c == step.condition).unwrap_or(0);
+                    step.condition = if command == 'i' {
+                        all[(at + 1) % all.len()]
+                    } else {
+                        all[(at + all.len() - 1) % all.len()]
+                    };
+                    sequence.put_step(track, state.step, step)?;
+                    send_control = true;
+                }
+            }
+            // ratchets
+            't' => {
+                if let Some(mut step) = sequence.get_step(track, state.step) {
+                    step.ratchet = step.ratchet % MAX_RATCHET + 1;
+                    sequence.put_step(track, state.step, step)?;
+                    send_control = true;
+                }
+            }
+            'T' => {
+                if let Some(mut step) = sequence.get_step(track, state.step) {
+                    step.decay = !step.decay;
+                    sequence.put_step(track, state.step, step)?;
+                    send_control = true;
+                }
+            }
+            // micro timing, a 24th of a step at a time
+            'n' | 'N' => {
+                if let Some(mut step) = sequence.get_step(track, state.step) {
+                    step.nudge = if command == 'n' {
+                        (step.nudge - 1).max(-MAX_NUDGE)
+                    } else {
+                        (step.nudge + 1).min(MAX_NUDGE)
+                    };
+                    sequence.put_step(track, state.step, step)?;
+                    send_control = true;
+                }
+            }
+            // track length
+            ']' => {
+                sequence.set_track_steps(track, sequence.track_steps(track) + 1)?;
+                send_control = true;
+            }
+            '[' if sequence.track_steps(track) > 1 => {
+                sequence.set_track_steps(track, sequence.track_steps(track) - 1)?;
+                send_control = true;
+            }
+            // pattern bank, edit one while another plays
+            '}' if state.edit + 1 < state.bank.len() => state.edit += 1,
+            '{' if state.edit > 0 => state.edit -= 1,
+            'p' => {
+                state.cued = state.edit;
                 send_control = true;
             }
             '+' => {
@@ -127,29 +637,386 @@ pub fn play(display: &impl Ui, steps: usize, divisions: u32) -> Result<(), Box<d
                 state.tempo -= 1;
                 send_control = true;
             }
+            // swing, for every track or just this one
+            '>' if state.swing < MAX_SWING => {
+                state.swing += 1;
+                send_control = true;
+            }
+            '<' if state.swing > 0 => {
+                state.swing -= 1;
+                send_control = true;
+            }
+            ')' if state.track_swing() < MAX_SWING => {
+                state.track_swing[state.selected_track] = Some(state.track_swing() + 1);
+                send_control = true;
+            }
+            '(' if state.track_swing() > 0 => {
+                state.track_swing[state.selected_track] = Some(state.track_swing() - 1);
+                send_control = true;
+            }
+            '=' => {
+                state.track_swing[state.selected_track] = None;
+                send_control = true;
+            }
+            // accent gains, a dB at a time
+            'z' | 'Z' | 'u' | 'U' | 'o' | 'O' => {
+                let level = match command.to_ascii_lowercase() {
+                    'z' => AccentLevel::Soft,
+                    'u' => AccentLevel::Regular,
+                    _ => AccentLevel::Loud,
+                };
+                let db = if command.is_ascii_uppercase() {
+                    1.0
+                } else {
+                    -1.0
+                };
+                state.accents_mut().adjust(level, db);
+                send_control = true;
+            }
+            'g' => state.track_accent_edit = !state.track_accent_edit,
+            'G' => {
+                state.track_accents[state.selected_track] = None;
+                send_control = true;
+            }
+            // the current track's channel strip
+            '/' | '*' => {
+                let db = if command == '*' { 1.0 } else { -1.0 };
+                state.mix[track].adjust_volume(db);
+                send_control = true;
+            }
+            ';' | '\'' => {
+                let by = if command == '\'' { PAN_STEP } else { -PAN_STEP };
+                state.mix[track].adjust_pan(by);
+                send_control = true;
+            }
+            'K' => {
+                let group = state.choke[track].map_or(1, |g| g + 1);
+                state.choke[track] = (group <= CHOKE_GROUPS).then_some(group);
+                send_control = true;
+            }
+            'M' => {
+                state.mix[track].mute = !state.mix[track].mute;
+                send_control = true;
+            }
+            'X' => {
+                state.mix[track].solo = !state.mix[track].solo;
+                send_control = true;
+            }
+            // song mode
+            'S' => {
+                state.song_mode = !state.song_mode;
+                send_control = true;
+            }
+            'a' => {
+                let at = (state.song_entry + 1).min(state.song.entries.len());
+                let entry = SongEntry {
+                    pattern: state.edit,
+                    repeats: 1,
+                };
+                state.song.insert(at, entry)?;
+                state.song_entry = at;
+                send_control = true;
+            }
+            'x' if state.song_entry < state.song.entries.len() => {
+                state.song.remove(state.song_entry)?;
+                state.song_entry = state
+                    .song_entry
+                    .min(state.song.entries.len().saturating_sub(1));
+                send_control = true;
+            }
+            '.' if state.song_entry + 1 < state.song.entries.len() => state.song_entry += 1,
+            ',' if state.song_entry > 0 => state.song_entry -= 1,
+            'r' if state.song_entry < state.song.entries.len() => {
+                state.song.entries[state.song_entry].repeats += 1;
+                send_control = true;
+            }
+            'R' if state
+                .song
+                .entries
+                .get(state.song_entry)
+                .is_some_and(|e| e.repeats > 1) =>
+            {
+                state.song.entries[state.song_entry].repeats -= 1;
+                send_control = true;
+            }
+            'b' if state.song_entry < state.song.entries.len() => {
+                state.song.set_loop_start(state.song_entry)?;
+                send_control = true;
+            }
+            'e' if state.song_entry < state.song.entries.len() => {
+                state.song.set_loop_end(state.song_entry)?;
+                send_control = true;
+            }
+            'B' => {
+                state.song.loop_points = None;
+                send_control = true;
+            }
+            '!' => {
+                state.song.stop_at_end = !state.song.stop_at_end;
+                send_control = true;
+            }
+            // kits, switched without stopping
+            'y' | 'Y' => {
+                state.message = match state.switch_kit(library, command == 'y') {
+                    Ok((from, warnings)) => {
+                        sample_tx.send((state.samples.clone(), from))?;
+                        send_control = true;
+                        send_bank = true;
+                        format!("kit {} {}", state.kit, warnings.join(" "))
+                    }
+                    Err(e) => format!("cant switch kit: {}", e),
+                };
+            }
+            // save / load
+            'W' => {
+                state.message = match state.pattern().save(&path) {
+                    Ok(()) => format!("saved {}", path.display()),
+                    Err(e) => format!("cant save {}: {}", path.display(), e),
+                };
+            }
+            'E' => {
+                state.message = match state.reload(&path, library) {
+                    Ok(warnings) => {
+                        let from = (0..state.samples.len()).map(Some).collect();
+                        sample_tx.send((state.samples.clone(), from))?;
+                        format!("opened {} {}", path.display(), warnings.join(" "))
+                    }
+                    Err(e) => format!("cant open {}: {}", path.display(), e),
+                };
+                send_control = true;
+                send_bank = true;
+            }
             _ => {}
         }
 
-        if command != '0' {
+        // keep the cursor on the selected track, they can be different lengths
+        let track_steps = state.bank[state.edit].track_steps(state.selected_track);
+        if state.step >= track_steps {
+            state.step = track_steps.saturating_sub(1);
+        }
+
+        if command != '0' || reload || heard {
             display.update(UIContent {
                 muted: state.muted,
                 tempo: state.tempo,
                 step: state.step,
                 track: state.selected_track,
-                divisions,
-                sequence: &state.sequence,
+                divisions: state.divisions,
+                swing: state.swing,
+                track_swing: &state.track_swing,
+                accents: state.accents,
+                track_accents: &state.track_accents,
+                track_accent_edit: state.track_accent_edit,
+                kit: &state.kit,
+                samples: &state.samples,
+                mix: &state.mix,
+                effects: &state.effects,
+                bus_effects: state.bus_effects,
+                fx_edit: state.fx_edit,
+                choke: &state.choke,
+                sequence: &state.bank[state.edit],
+                pattern: state.edit,
+                cued: state.cued,
+                song: &state.song,
+                song_mode: state.song_mode,
+                song_entry: state.song_entry,
+                fill: state.fill,
+                step_edit: state.step_edit,
+                record: state.record,
+                quantize: state.recorder.quantize,
+                overdub: state.recorder.overdub,
+                playhead: state.playhead,
+                message: &state.message,
             })
         }
 
         if send_control {
-            control_tx.send(Controls {
-                tempo: state.tempo,
-                mute: state.muted,
-            })?;
+            control_tx.send(state.controls())?;
+
+            if send_bank {
+                for (slot, sequence) in state.bank.iter().enumerate() {
+                    seq_tx.send((slot, sequence.get_sequence()))?;
+                }
+            } else {
+                seq_tx.send((state.edit, state.bank[state.edit].get_sequence()))?;
+            }
+        }
+    }
+}
 
-            seq_tx.send(state.sequence.get_sequence())?;
+impl State {
+    /// the current beat as a savable pattern
+    fn pattern(&self) -> Pattern {
+        Pattern {
+            tempo: self.tempo,
+            divisions: self.divisions,
+            bank: self.bank.iter().map(|s| s.get_sequence()).collect(),
+            play: self.cued,
+            kit: Some(self.kit.clone()),
+            song: self.song.clone(),
+            seed: self.seed,
+            curve: self.curve.clone(),
+            accents: self.accents,
+            track_accents: self.track_accents.clone(),
+            mix: self.mix.clone(),
+            effects: self.effects.clone(),
+            bus_effects: self.bus_effects,
+            choke: self.choke.clone(),
+            samples: self.sample_map.clone(),
+            swing: self.swing,
+            track_swing: self.track_swing.clone(),
         }
     }
 
-    Ok(())
+    /// what the playback thread needs to know
+    fn controls(&self) -> Controls {
+        Controls {
+            tempo: self.tempo,
+            divisions: self.divisions,
+            mute: self.muted,
+            swing: self.swing,
+            track_swing: self.track_swing.clone(),
+            pattern: self.cued,
+            fill: self.fill,
+            curve: self.curve.clone(),
+            accents: self.accents,
+            track_accents: self.track_accents.clone(),
+            mix: self.mix.clone(),
+            effects: self.effects.clone(),
+            bus_effects: self.bus_effects,
+            choke: self.choke.clone(),
+            song: if self.song_mode && !self.song.entries.is_empty() {
+                Some(self.song.clone())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// the swing the selected track plays with, its own or the global one
+    fn track_swing(&self) -> u32 {
+        self.track_swing[self.selected_track].unwrap_or(self.swing)
+    }
+
+    /// the accent gains the accent keys change, the selected track's own ones get made
+    /// from the global ones if it doesnt have any yet
+    fn accents_mut(&mut self) -> &mut AccentGains {
+        if self.track_accent_edit {
+            self.track_accents[self.selected_track].get_or_insert(self.accents)
+        } else {
+            &mut self.accents
+        }
+    }
+
+    /// put a hit played in while recording into the pattern it landed in
+    ///
+    /// returns an error if the step isnt in the pattern anymore
+    fn record_hit(&mut self, hit: Recorded) -> Result<(), &'static str> {
+        let sequence = self
+            .bank
+            .get_mut(hit.slot)
+            .ok_or("no pattern in that slot")?;
+        self.recorder.write(sequence, hit)
+    }
+
+    /// change to the next or previous kit in the library, the tracks play its samples in
+    /// order and keep their settings. there's new empty tracks if it has more samples, and
+    /// the ones past the end go if it has less. the tracks take the kit's choke groups
+    ///
+    /// returns the track each one was before and a warning for every sample that wouldnt
+    /// load, or an error if the kit cant be loaded, the current one is left alone if so
+    fn switch_kit(&mut self, library: &Library, next: bool) -> Result<KitChange, Box<dyn Error>> {
+        let kits = library.kits()?;
+        let at = match kits.iter().position(|k| *k == self.kit) {
+            Some(at) if next => (at + 1) % kits.len(),
+            Some(at) => (at + kits.len() - 1) % kits.len(),
+            None => 0,
+        };
+        let kit = kits.get(at).ok_or("theres no kits")?;
+        let (kit_samples, warnings) = library.load(kit)?;
+
+        let from: Vec<Option<usize>> = (0..kit_samples.len()).map(Some).collect();
+        self.choke = kit_samples.iter().map(|s| s.choke).collect();
+        self.kit = kit.clone();
+        self.move_tracks(kit_samples, (0..from.len()).collect(), &from);
+        Ok((from, warnings))
+    }
+
+    /// load the kit again after its files changed, tracks stay with their samples
+    ///
+    /// returns the track each one was before and a warning for every sample that wouldnt
+    /// load, or an error if the kit cant be loaded, the current one is left alone if so
+    fn reload_kit(&mut self, library: &Library) -> Result<KitChange, Box<dyn Error>> {
+        let (kit_samples, warnings) = library.reload(&self.kit, &self.kit_samples)?;
+        let (map, from) = samples::rematch(&self.kit_samples, &kit_samples, &self.sample_map);
+        self.choke = samples::remap(&self.choke, &from, None);
+        // new tracks get the kit's choke groups
+        for (track, was) in from.iter().enumerate() {
+            if was.is_none() {
+                self.choke[track] = kit_samples[map[track]].choke;
+            }
+        }
+        self.move_tracks(kit_samples, map, &from);
+        Ok((from, warnings))
+    }
+
+    /// put the tracks on other samples, their steps and settings go with them
+    ///
+    /// sample_map: which of the kit's samples each track plays
+    ///
+    /// from: the track each one was before, None for new ones
+    fn move_tracks(
+        &mut self,
+        kit_samples: samples::ActiveSamples,
+        sample_map: Vec<usize>,
+        from: &[Option<usize>],
+    ) {
+        for sequence in &mut self.bank {
+            sequence.remap_tracks(from);
+        }
+        self.track_swing = samples::remap(&self.track_swing, from, None);
+        self.track_accents = samples::remap(&self.track_accents, from, None);
+        self.mix = samples::remap(&self.mix, from, ChannelStrip::default());
+        self.effects = samples::remap(&self.effects, from, Effects::default());
+        self.choke.resize(from.len(), None);
+        self.samples = sample_map.iter().map(|&s| kit_samples[s].clone()).collect();
+        self.sample_map = sample_map;
+        self.kit_samples = kit_samples;
+        self.selected_track = self.selected_track.min(from.len().saturating_sub(1));
+    }
+
+    /// replace the current beat with the one in a pattern file, and its kit
+    ///
+    /// returns a warning for every sample that wouldnt load, or an error if the file is bad
+    /// or doesnt fit its kit, the current beat is left alone if so
+    fn reload(&mut self, path: &Path, library: &Library) -> Result<Vec<String>, Box<dyn Error>> {
+        let pattern = Pattern::load(path)?;
+        let (kit, kit_samples, warnings) = load_kit(&pattern, library)?;
+        validate(&pattern, &kit_samples, &warnings)?;
+
+        self.samples = pattern.track_samples(&kit_samples);
+        self.sample_map = pattern.samples;
+        self.kit = kit;
+        self.kit_samples = kit_samples;
+        self.tempo = pattern.tempo;
+        self.divisions = pattern.divisions;
+        self.swing = pattern.swing;
+        self.track_swing = pattern.track_swing;
+        self.edit = pattern.play;
+        self.cued = pattern.play;
+        self.bank = pattern.bank;
+        self.song = pattern.song;
+        self.seed = pattern.seed;
+        self.curve = pattern.curve;
+        self.accents = pattern.accents;
+        self.track_accents = pattern.track_accents;
+        self.mix = pattern.mix;
+        self.effects = pattern.effects;
+        self.bus_effects = pattern.bus_effects;
+        self.choke = pattern.choke;
+        self.song_entry = 0;
+        self.selected_track = 0;
+        self.step = 0;
+        Ok(warnings)
+    }
 }
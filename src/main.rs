@@ -1,6 +1,9 @@
+use std::path::PathBuf;
 use std::{env, process};
 
+use fungus::samples::Library;
 use fungus::ui::Ui;
+use fungus::Session;
 
 #[cfg(not(pi))]
 use fungus::test_ui::Display;
@@ -8,31 +11,160 @@ use fungus::test_ui::Display;
 #[cfg(pi)]
 use fungus::actual_ui::HardUi;
 
+static DEFAULT_PATTERN: &str = "pattern.fungus";
+
+static USAGE: &str = "usage:
+    fungus <steps> <division> [pattern file]
+    fungus open <pattern file>
+    fungus render <pattern file> <wav file> [loops | song]
+    fungus export <pattern file> <midi file> [drum map file]
+    fungus import <midi file> <pattern file> <steps> <division> [drum map file]
+
+options:
+    --samples <dir>    where the kits are, ./samples or $XDG_DATA_HOME/fungus/samples if not";
+
 fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // options can go anywhere
+    let library = match args.iter().position(|a| a == "--samples") {
+        Some(at) if at + 1 < args.len() => {
+            let dir = args.remove(at + 1);
+            args.remove(at);
+            Library::new(&PathBuf::from(dir))
+        }
+        Some(_) => {
+            println!("{}", USAGE);
+            process::exit(1);
+        }
+        None => Library::find(),
+    };
+    let mut args = args.into_iter();
+
+    let session = match args.next().as_deref() {
+        Some("render") => {
+            let (pattern, out) = match (args.next(), args.next()) {
+                (Some(p), Some(o)) => (PathBuf::from(p), PathBuf::from(o)),
+                _ => {
+                    println!("{}", USAGE);
+                    process::exit(1);
+                }
+            };
+            let result = match args.next().as_deref() {
+                Some("song") => fungus::render_song(&pattern, &out, &library),
+                Some(a) => match a.parse::<u32>().unwrap_or(0) {
+                    0 => {
+                        println!("gotta render at least 1 loop");
+                        process::exit(1);
+                    }
+                    loops => fungus::render(&pattern, &out, loops, &library),
+                },
+                None => fungus::render(&pattern, &out, 1, &library),
+            };
+
+            match result {
+                Ok(warnings) => {
+                    for warning in warnings {
+                        println!("{}", warning);
+                    }
+                }
+                Err(e) => {
+                    println!("{}", e);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some("export") => {
+            let (pattern, out) = match (args.next(), args.next()) {
+                (Some(p), Some(o)) => (PathBuf::from(p), PathBuf::from(o)),
+                _ => {
+                    println!("{}", USAGE);
+                    process::exit(1);
+                }
+            };
+            let drum_map = args.next().map(PathBuf::from);
+
+            if let Err(e) = fungus::export_midi(&pattern, &out, drum_map.as_deref()) {
+                println!("{}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        Some("import") => {
+            let (midi, out) = match (args.next(), args.next()) {
+                (Some(m), Some(o)) => (PathBuf::from(m), PathBuf::from(o)),
+                _ => {
+                    println!("{}", USAGE);
+                    process::exit(1);
+                }
+            };
+            let steps = args
+                .next()
+                .and_then(|a| a.parse::<usize>().ok())
+                .unwrap_or(0);
+            let divisions = args.next().and_then(|a| a.parse::<u32>().ok()).unwrap_or(0);
+            if steps == 0 || divisions == 0 {
+                println!("gotta have at least 1 step. divisions must be >= 1");
+                process::exit(1);
+            }
+            let drum_map = args.next().map(PathBuf::from);
+
+            let imported =
+                fungus::import_midi(&midi, &out, steps, divisions, drum_map.as_deref(), &library);
+            match imported {
+                Ok(report) => {
+                    for line in report {
+                        println!("{}", line);
+                    }
+                }
+                Err(e) => {
+                    println!("{}", e);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some("open") => match args.next() {
+            Some(path) => Session::Open {
+                path: PathBuf::from(path),
+            },
+            None => {
+                println!("{}", USAGE);
+                process::exit(1);
+            }
+        },
+        first => {
+            let steps = match first {
+                Some(a) => a.parse::<usize>().unwrap_or(0),
+                None => 0,
+            };
+            let divisions = match args.next() {
+                Some(a) => a.parse::<u32>().unwrap_or(0),
+                None => 1,
+            };
+
+            if steps == 0 || divisions == 0 {
+                println!("gotta have at least 1 step. divisions must be >= 1");
+                println!("{}", USAGE);
+                process::exit(1);
+            }
+
+            Session::New {
+                steps,
+                divisions,
+                path: PathBuf::from(args.next().unwrap_or(DEFAULT_PATTERN.to_string())),
+            }
+        }
+    };
+
     #[cfg(not(pi))]
     let disp = Display::new();
     #[cfg(pi)]
     let disp = HardUi::new().unwrap();
 
-    let mut args = env::args();
-    args.next();
-    let steps = match args.next() {
-        Some(a) => a.parse::<usize>().unwrap(),
-        None => 0,
-    };
-    let divisions = match args.next() {
-        Some(a) => a.parse::<u32>().unwrap(),
-        None => 1,
-    };
-
-    if steps <= 0 || divisions <= 0 {
-        println!("gotta have at least 1 step. divisions must be >= 1");
-        process::exit(1);
-    }
-
-    match fungus::play(&disp, steps, divisions) {
-        Err(e) => println!("{}", e),
-        Ok(()) => (),
-    }
+    let result = fungus::play(&disp, session, &library);
     disp.end();
+    if let Err(e) = result {
+        println!("{}", e);
+    }
 }
@@ -0,0 +1,582 @@
+/**
+Standard MIDI File import and export, so beats can go to and from a DAW.
+
+Exported files are type 1: a tempo track, then one track per sequencer track on the
+General MIDI drum channel. Every step is `TICKS_PER_STEP` ticks long. Steps that might not
+play in fungus always play in the export, a midi file has no dice. Ratchets come out as
+separate notes, imports only see the first of them. Nudged steps are off the grid by a
+tick for every 24th of a step, imports snap them back onto it. Sound locks stay behind,
+theres no drum channel way to say them.
+
+Imports take type 0 or 1 files, notes on any channel.
+*/
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::pattern::Pattern;
+use crate::sequencer::{Sequence, MAX_VELOCITY, NUDGES_PER_STEP};
+
+/// midi ticks a step, leaves room for note offs between steps
+pub const TICKS_PER_STEP: u32 = 24;
+
+/// General MIDI drums live on channel 10
+static DRUM_CHANNEL: u8 = 9;
+
+/// kick, snare, closed hat, open hat, clap, low tom, mid tom, high tom, crash, ride
+static GM_DRUMS: [u8; 10] = [36, 38, 42, 46, 39, 45, 47, 50, 49, 51];
+
+/// Which midi notes each sequencer track plays
+pub struct DrumMap {
+    /// one entry per track, the first note is the one that gets written out
+    notes: Vec<Vec<u8>>,
+}
+
+impl Default for DrumMap {
+    /// General MIDI kit, wraps around if there are more tracks than drums
+    fn default() -> Self {
+        DrumMap {
+            notes: GM_DRUMS.iter().map(|&n| vec![n]).collect(),
+        }
+    }
+}
+
+impl DrumMap {
+    /// read a drum map file
+    ///
+    /// one line per track, with one or more note numbers. blank lines and lines
+    /// starting with `#` are ignored, eg:
+    ///
+    /// ```text
+    /// # kick
+    /// 36 35
+    /// # snare
+    /// 38 40
+    /// ```
+    pub fn load(path: &Path) -> Result<DrumMap, Box<dyn Error>> {
+        DrumMap::parse(&fs::read_to_string(path)?)
+    }
+
+    /// read a drum map from text, see `load`
+    pub fn parse(text: &str) -> Result<DrumMap, Box<dyn Error>> {
+        let mut notes = vec![];
+        for (num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let track = line
+                .split_whitespace()
+                .map(|n| match n.parse::<u8>() {
+                    Ok(n) if n < 128 => Ok(n),
+                    _ => Err(format!("line {}: '{}' is not a midi note", num + 1, n)),
+                })
+                .collect::<Result<Vec<u8>, String>>()?;
+            notes.push(track);
+        }
+        if notes.is_empty() {
+            return Err("drum map has no notes".into());
+        }
+        Ok(DrumMap { notes })
+    }
+
+    /// the note a track gets written out as
+    pub fn note(&self, track: usize) -> u8 {
+        self.notes[track % self.notes.len()][0]
+    }
+
+    /// the first track that plays a note, if any
+    pub fn track(&self, note: u8) -> Option<usize> {
+        self.notes.iter().position(|notes| notes.contains(&note))
+    }
+}
+
+/// Write the pattern that plays first out as a midi file
+pub fn export(pattern: &Pattern, map: &DrumMap, path: &Path) -> Result<(), Box<dyn Error>> {
+    fs::write(path, to_smf(pattern, map)?)?;
+    Ok(())
+}
+
+/// the bytes of a type 1 midi file for a pattern
+///
+/// returns an error if divisions is too big to fit a midi file's resolution
+pub fn to_smf(pattern: &Pattern, map: &DrumMap) -> Result<Vec<u8>, Box<dyn Error>> {
+    let ppq = pattern.divisions * TICKS_PER_STEP;
+    if ppq >= 0x8000 {
+        return Err("too many divisions to fit in a midi file".into());
+    }
+
+    let tracks = pattern.sequence().tracks();
+    let mut out = vec![];
+    out.extend_from_slice(b"MThd");
+    out.extend_from_slice(&6u32.to_be_bytes());
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&(tracks.len() as u16 + 1).to_be_bytes());
+    out.extend_from_slice(&(ppq as u16).to_be_bytes());
+
+    // tempo track
+    let mut events = vec![];
+    let us_per_beat = 60_000_000 / pattern.tempo;
+    events.push((0, vec![0xff, 0x51, 0x03]));
+    events[0]
+        .1
+        .extend_from_slice(&us_per_beat.to_be_bytes()[1..]);
+    write_track(
+        &mut out,
+        events,
+        pattern.sequence().steps() as u32 * TICKS_PER_STEP,
+    );
+
+    // shorter tracks repeat until the longest one is done, like they do in playback
+    for (i, track) in tracks.iter().enumerate() {
+        let note = map.note(i);
+        let mut events = vec![];
+        for step in 0..pattern.sequence().steps() {
+            let hit = track[step % track.len()];
+            if hit.velocity == 0 {
+                continue;
+            }
+            // swung steps go late, and get let go of halfway to the next step
+            let late = if step % 2 == 1 {
+                pattern.track_swing(i) * TICKS_PER_STEP / 100
+            } else {
+                0
+            };
+            // a nudge is a 24th of a step, a tick here. nudged before the start it goes at
+            // the start
+            let start =
+                (step as u32 * TICKS_PER_STEP + late).saturating_add_signed(nudge_ticks(hit.nudge));
+            // ratchets split whats left of the step between their hits, no more hits than
+            // there are ticks left so they dont stack up on the same tick
+            let room = TICKS_PER_STEP - late;
+            let mut gains = hit.ratchet_gains();
+            gains.truncate(room as usize);
+            let gap = room / gains.len() as u32;
+            for (n, gain) in gains.into_iter().enumerate() {
+                let on = start + gap * n as u32;
+                let vel = ((hit.velocity as f64 * gain).round() as u8).max(1);
+                events.push((on, vec![0x90 | DRUM_CHANNEL, note, vel]));
+                // held for half the gap, at least a tick or its over before it starts
+                events.push((on + (gap / 2).max(1), vec![0x80 | DRUM_CHANNEL, note, 0]));
+            }
+        }
+        // note offs before note ons when they land on the same tick
+        events.sort_by_key(|(tick, e)| (*tick, e[0] & 0xf0 == 0x90));
+        write_track(
+            &mut out,
+            events,
+            pattern.sequence().steps() as u32 * TICKS_PER_STEP,
+        );
+    }
+
+    Ok(out)
+}
+
+/// midi ticks a step gets moved by its nudge
+fn nudge_ticks(nudge: i8) -> i32 {
+    nudge as i32 * TICKS_PER_STEP as i32 / NUDGES_PER_STEP as i32
+}
+
+/// Read a midi file into a new pattern
+///
+/// tracks: how many sequencer tracks the pattern gets, one per sample
+///
+/// steps, divisions: the grid notes get quantized to, divisions steps to a beat
+///
+/// returns the pattern, and a list of the notes that were moved or dropped to make it fit
+pub fn import(
+    path: &Path,
+    map: &DrumMap,
+    tracks: usize,
+    steps: usize,
+    divisions: u32,
+) -> Result<(Pattern, Vec<String>), Box<dyn Error>> {
+    from_smf(&fs::read(path)?, map, tracks, steps, divisions)
+}
+
+/// read the bytes of a midi file into a new pattern, see `import`
+pub fn from_smf(
+    smf: &[u8],
+    map: &DrumMap,
+    tracks: usize,
+    steps: usize,
+    divisions: u32,
+) -> Result<(Pattern, Vec<String>), Box<dyn Error>> {
+    let mut reader = Reader { data: smf, pos: 0 };
+    if reader.take(4)? != b"MThd" {
+        return Err("not a midi file".into());
+    }
+    let header_len = reader.u32()? as usize;
+    let header = reader.take(header_len)?;
+    if header_len < 6 {
+        return Err("midi header is too short".into());
+    }
+    let num_tracks = u16::from_be_bytes([header[2], header[3]]);
+    let ppq = u16::from_be_bytes([header[4], header[5]]);
+    if ppq & 0x8000 != 0 || ppq == 0 {
+        return Err("midi files timed in SMPTE frames are not supported".into());
+    }
+
+    // every note on in the file: (tick, note, velocity)
+    let mut notes = vec![];
+    let mut tempo = None;
+    for _ in 0..num_tracks {
+        let id = reader.take(4)?;
+        let len = reader.u32()? as usize;
+        let chunk = reader.take(len)?;
+        if id == b"MTrk" {
+            read_track(chunk, &mut notes, &mut tempo)?;
+        }
+    }
+
+    let mut pattern = Pattern::new(tracks, steps, divisions, tempo.unwrap_or(120));
+    let mut report = vec![];
+    let step_ticks = ppq as f64 / divisions as f64;
+    notes.sort_by_key(|n| n.0);
+
+    for (tick, note, vel) in notes {
+        let track = match map.track(note) {
+            Some(t) if t < tracks => t,
+            _ => {
+                report.push(format!(
+                    "note {} at tick {} has no track in the drum map, dropped",
+                    note, tick
+                ));
+                continue;
+            }
+        };
+
+        let exact = tick as f64 / step_ticks;
+        let step = exact.round() as usize;
+        if step >= steps {
+            report.push(format!(
+                "note {} at tick {} is past the last step, dropped",
+                note, tick
+            ));
+            continue;
+        }
+        if (exact - step as f64).abs() * step_ticks >= 0.5 {
+            report.push(format!(
+                "note {} at tick {} is off the grid, moved to step {}",
+                note, tick, step
+            ));
+        }
+
+        // two notes on one step keep the louder one
+        let sequence = pattern.sequence_mut();
+        if let Some(mut hit) = sequence.get_step(track, step) {
+            if vel > hit.velocity {
+                hit.velocity = vel.min(MAX_VELOCITY);
+                sequence.put_step(track, step, hit)?;
+            }
+        }
+    }
+
+    Ok((pattern, report))
+}
+
+/// pull the note ons and the first tempo out of an MTrk chunk
+fn read_track(
+    chunk: &[u8],
+    notes: &mut Vec<(u32, u8, u8)>,
+    tempo: &mut Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = Reader {
+        data: chunk,
+        pos: 0,
+    };
+    let mut tick = 0;
+    let mut status = 0;
+
+    while reader.pos < chunk.len() {
+        tick += reader.vlq()?;
+        let mut byte = reader.byte()?;
+        match byte {
+            0xff => {
+                let kind = reader.byte()?;
+                let len = reader.vlq()? as usize;
+                let data = reader.take(len)?;
+                if kind == 0x51 && len == 3 && tempo.is_none() {
+                    let us_per_beat = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                    if us_per_beat > 0 {
+                        *tempo = Some((60_000_000.0 / us_per_beat as f64).round() as u32);
+                    }
+                }
+                if kind == 0x2f {
+                    break;
+                }
+            }
+            0xf0 | 0xf7 => {
+                let len = reader.vlq()? as usize;
+                reader.take(len)?;
+            }
+            _ => {
+                // running status: reuse the last status byte, this one is data
+                let first = if byte & 0x80 == 0 {
+                    let data = byte;
+                    byte = status;
+                    data
+                } else {
+                    status = byte;
+                    reader.byte()?
+                };
+                match byte & 0xf0 {
+                    0xc0 | 0xd0 => {}
+                    0x80 | 0xa0 | 0xb0 | 0xe0 => {
+                        reader.byte()?;
+                    }
+                    0x90 => {
+                        let vel = reader.byte()?;
+                        // note on with no velocity is a note off
+                        if vel > 0 {
+                            notes.push((tick, first, vel));
+                        }
+                    }
+                    _ => return Err("bad midi event".into()),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// walks through midi bytes, running off the end is an error
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if self.pos + len > self.data.len() {
+            return Err("midi file ends too early".into());
+        }
+        let out = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(out)
+    }
+
+    fn byte(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn vlq(&mut self) -> Result<u32, Box<dyn Error>> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let b = self.byte()?;
+            value = (value << 7) | (b & 0x7f) as u32;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("midi number is too long".into())
+    }
+}
+
+/// append an MTrk chunk
+///
+/// events: (absolute tick, event bytes), sorted by tick
+///
+/// end: tick of the end of track, so loops come out the right length
+fn write_track(out: &mut Vec<u8>, events: Vec<(u32, Vec<u8>)>, end: u32) {
+    let mut data = vec![];
+    let mut last = 0;
+    for (tick, event) in events {
+        write_vlq(&mut data, tick - last);
+        data.extend_from_slice(&event);
+        last = tick;
+    }
+    write_vlq(&mut data, end.saturating_sub(last));
+    data.extend_from_slice(&[0xff, 0x2f, 0x00]);
+
+    out.extend_from_slice(b"MTrk");
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(&data);
+}
+
+/// midi variable length quantity, 7 bits a byte, most significant first
+fn write_vlq(out: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.reverse();
+    out.extend_from_slice(&bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_smf, to_smf, write_vlq, DrumMap};
+    use crate::pattern::Pattern;
+    use crate::playback::MAX_SWING;
+    use crate::sequencer::{AccentLevel, Sequence, Step};
+
+    #[test]
+    fn vlq() {
+        let mut out = vec![];
+        write_vlq(&mut out, 0);
+        write_vlq(&mut out, 0x7f);
+        write_vlq(&mut out, 0x80);
+        write_vlq(&mut out, 0x0fffffff);
+        assert_eq!(out, vec![0x00, 0x7f, 0x81, 0x00, 0xff, 0xff, 0xff, 0x7f]);
+    }
+
+    #[test]
+    fn exporting() {
+        let mut p = Pattern::new(2, 4, 4, 120);
+        p.sequence_mut().set_step(1, 2, AccentLevel::Loud).unwrap();
+        let map = DrumMap::parse("36\n38 40").unwrap();
+
+        let smf = to_smf(&p, &map).unwrap();
+        assert_eq!(&smf[0..4], b"MThd");
+        // format 1, tempo track + 2 tracks, 96 ticks a beat
+        assert_eq!(&smf[8..14], &[0, 1, 0, 3, 0, 96]);
+        // 120bpm is 500000us a beat
+        let tempo = smf
+            .windows(3)
+            .position(|w| w == [0xff, 0x51, 0x03])
+            .unwrap();
+        assert_eq!(&smf[tempo + 3..tempo + 6], &[0x07, 0xa1, 0x20]);
+        // loud snare on step 2, tick 48
+        let on = smf.windows(3).position(|w| w == [0x99, 38, 127]).unwrap();
+        assert_eq!(smf[on - 1], 48);
+    }
+
+    #[test]
+    fn swung_export() {
+        let mut p = Pattern::new(1, 4, 4, 120);
+        p.swing = 50;
+        p.sequence_mut().set_step(0, 1, AccentLevel::Loud).unwrap();
+
+        // step 1 is tick 24, half a step late is tick 36
+        let smf = to_smf(&p, &DrumMap::default()).unwrap();
+        let on = smf.windows(3).position(|w| w == [0x99, 36, 127]).unwrap();
+        assert_eq!(smf[on - 1], 36);
+    }
+
+    #[test]
+    fn ratchet_export() {
+        let mut p = Pattern::new(1, 4, 4, 120);
+        let step = Step {
+            ratchet: 4,
+            decay: true,
+            ..AccentLevel::Loud.into()
+        };
+        p.sequence_mut().put_step(0, 1, step).unwrap();
+
+        // 4 hits 6 ticks apart from tick 24, each quieter than the last and let go of
+        // 3 ticks later
+        let smf = to_smf(&p, &DrumMap::default()).unwrap();
+        for (delta, vel) in [(24, 127), (3, 95), (3, 64), (3, 32)] {
+            let on = smf.windows(3).position(|w| w == [0x99, 36, vel]).unwrap();
+            assert_eq!(smf[on - 1], delta);
+        }
+
+        // with full swing the step is 18 ticks late, so the 4 hits get a tick each. every
+        // one is let go of a tick after it starts instead of on the same tick
+        p.swing = MAX_SWING;
+        let smf = to_smf(&p, &DrumMap::default()).unwrap();
+        for vel in [127, 95, 64, 32] {
+            let on = smf.windows(3).position(|w| w == [0x99, 36, vel]).unwrap();
+            assert_eq!(smf[on + 3..on + 7], [1, 0x89, 36, 0]);
+        }
+    }
+
+    #[test]
+    fn nudged_export() {
+        let mut p = Pattern::new(1, 4, 4, 120);
+        let early = Step {
+            nudge: -3,
+            ..AccentLevel::Loud.into()
+        };
+        p.sequence_mut().put_step(0, 2, early).unwrap();
+
+        // step 2 is tick 48, 3 24ths early is tick 45
+        let smf = to_smf(&p, &DrumMap::default()).unwrap();
+        let on = smf.windows(3).position(|w| w == [0x99, 36, 127]).unwrap();
+        assert_eq!(smf[on - 1], 45);
+    }
+
+    #[test]
+    fn short_tracks_repeat() {
+        let mut p = Pattern::new(2, 4, 4, 120);
+        p.sequence_mut().set_track_steps(1, 3).unwrap();
+        p.sequence_mut()
+            .set_step(1, 0, AccentLevel::Regular)
+            .unwrap();
+        let map = DrumMap::default();
+
+        let (back, _) = from_smf(&to_smf(&p, &map).unwrap(), &map, 2, 4, 4).unwrap();
+        let t = back.sequence().tracks();
+        assert_eq!(t[1][0], AccentLevel::Regular);
+        assert_eq!(t[1][3], AccentLevel::Regular);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut p = Pattern::new(3, 8, 4, 133);
+        p.sequence_mut().set_step(0, 0, AccentLevel::Loud).unwrap();
+        p.sequence_mut().set_step(1, 4, AccentLevel::Soft).unwrap();
+        p.sequence_mut()
+            .set_step(2, 7, AccentLevel::Regular)
+            .unwrap();
+        // velocities come back exactly
+        let quiet = Step {
+            velocity: 13,
+            ..Default::default()
+        };
+        p.sequence_mut().put_step(2, 2, quiet).unwrap();
+        let map = DrumMap::default();
+
+        let smf = to_smf(&p, &map).unwrap();
+        let (back, report) = from_smf(&smf, &map, 3, 8, 4).unwrap();
+        assert!(report.is_empty(), "{:?}", report);
+        assert_eq!(back.tempo, 133);
+        assert_eq!(back.sequence().tracks(), p.sequence().tracks());
+    }
+
+    #[test]
+    fn importing_reports() {
+        // type 0, 96 ticks a beat, running status note ons
+        let mut track = vec![];
+        track.extend_from_slice(&[0x00, 0x99, 36, 100]); // kick on step 0
+        track.extend_from_slice(&[0x1a, 38, 50]); // snare on tick 26, a bit late for step 1
+        track.extend_from_slice(&[0x00, 61, 90]); // no track for this one
+        track.extend_from_slice(&[0x81, 0x00, 36, 120]); // kick on tick 154, past the 4 steps
+        track.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+        let mut smf = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk".to_vec();
+        smf.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        smf.extend_from_slice(&track);
+
+        let map = DrumMap::default();
+        let (p, report) = from_smf(&smf, &map, 2, 4, 4).unwrap();
+        assert_eq!(p.tempo, 120);
+        let t = p.sequence().tracks();
+        assert_eq!(t[0][0], AccentLevel::Regular);
+        assert_eq!(t[1][1], AccentLevel::Soft);
+        assert_eq!(report.len(), 3, "{:?}", report);
+        assert!(report[0].contains("off the grid"));
+        assert!(report[1].contains("no track"));
+        assert!(report[2].contains("past the last step"));
+    }
+
+    #[test]
+    fn drum_maps() {
+        assert!(DrumMap::parse("").is_err());
+        assert!(DrumMap::parse("36\n200").is_err());
+        let map = DrumMap::parse("# kick\n36 35\n\n38").unwrap();
+        assert_eq!(map.note(0), 36);
+        assert_eq!(map.note(1), 38);
+        assert_eq!(map.note(2), 36);
+        assert_eq!(DrumMap::default().note(2), 42);
+        assert_eq!(map.track(35), Some(0));
+        assert_eq!(map.track(42), None);
+    }
+}
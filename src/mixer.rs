@@ -0,0 +1,78 @@
+/**
+The mixer, every track goes through its own channel strip with a volume, pan, mute and solo.
+
+Soloing a track mutes every track that isnt soloed, more than one can be soloed at once.
+Muting wins over soloing.
+*/
+/// the quietest and loudest a track's volume goes, in dB
+pub const VOLUME_RANGE: (f64, f64) = (-60.0, 12.0);
+
+/// how far a track pans either way, -100 is hard left and 100 hard right
+pub const MAX_PAN: i8 = 100;
+
+/// One track's fader, pan knob and mute and solo buttons
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct ChannelStrip {
+    /// dB
+    pub volume: f64,
+    /// -100 left to 100 right
+    pub pan: i8,
+    pub mute: bool,
+    pub solo: bool,
+}
+
+impl ChannelStrip {
+    /// turn the track up or down, it stays inside `VOLUME_RANGE`
+    pub fn adjust_volume(&mut self, db: f64) {
+        self.volume = (self.volume + db).clamp(VOLUME_RANGE.0, VOLUME_RANGE.1);
+    }
+
+    /// pan the track left or right, it stops at hard left or right
+    pub fn adjust_pan(&mut self, by: i8) {
+        self.pan = self.pan.saturating_add(by).clamp(-MAX_PAN, MAX_PAN);
+    }
+
+    /// pan for kira, 0 left to 1 right
+    pub fn panning(&self) -> f64 {
+        (self.pan as f64 + MAX_PAN as f64) / (2.0 * MAX_PAN as f64)
+    }
+}
+
+/// which tracks can be heard, the muted ones cant and if any are soloed only those can
+pub fn audible(strips: &[ChannelStrip]) -> Vec<bool> {
+    let soloing = strips.iter().any(|s| s.solo);
+    strips
+        .iter()
+        .map(|s| !s.mute && (s.solo || !soloing))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{audible, ChannelStrip};
+
+    #[test]
+    fn mute_and_solo() {
+        let mut strips = vec![ChannelStrip::default(); 3];
+        assert_eq!(audible(&strips), vec![true, true, true]);
+        strips[0].mute = true;
+        assert_eq!(audible(&strips), vec![false, true, true]);
+        strips[2].solo = true;
+        assert_eq!(audible(&strips), vec![false, false, true]);
+        strips[0].solo = true;
+        assert_eq!(audible(&strips), vec![false, false, true]);
+    }
+
+    #[test]
+    fn knobs_stop_at_the_ends() {
+        let mut strip = ChannelStrip::default();
+        strip.adjust_volume(-100.0);
+        assert_eq!(strip.volume, -60.0);
+        strip.adjust_pan(-120);
+        strip.adjust_pan(-10);
+        assert_eq!(strip.pan, -100);
+        assert_eq!(strip.panning(), 0.0);
+        strip.adjust_pan(100);
+        assert_eq!(strip.panning(), 0.5);
+    }
+}
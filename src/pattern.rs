@@ -0,0 +1,1044 @@
+/**
+Saving and loading patterns.
+
+A pattern file is plain text, one thing per line:
+
+```text
+fungus-pattern 1
+tempo 180
+divisions 4
+swing 20
+play 0
+kit 808
+track 0
+track 1 choke=1
+track 2 choke=1 swing=0 accents=-3,0,2 vol=-4.5 pan=-30 mute=on cutoff=40 reverb-send=30
+song 0x2 1x1 0x3 1x1
+song-loop 2 3
+song-end loop
+seed 0
+velocity-curve 1:-30 48:-6 96:0 127:6
+accents 0,0,0
+buses reverb-size=60 bus-delay=4
+pattern 0
+hits +___+___+___+___
+hits ____#_______#__-
+hits -+_-+_-+_-+_
+step 1 15 prob=50
+step 2 3 cond=1:4
+step 0 8 ratchet=3 decay=on
+step 1 12 nudge=-2
+step 2 4 pitch=-5 pan=30
+step 2 6 vel=60
+pattern 1
+hits +_+_+_+_
+hits ____#___
+hits ________
+```
+
+- the first line is the magic and the format version, bump it when the format changes
+- `tempo` is in BPM, `divisions` is steps to a beat
+- `swing` is how late every second step is, in percent of a step. its optional, 0 if missing
+- `play` is the slot in the bank that plays first, 0 if missing
+- `kit` is the kit the samples come out of, see `samples`. the first one in the library if
+  missing
+- every `track` line is one sequencer track, in order. the number is which sample
+  (index into the sample bank) the track plays, then it can have `key=value` settings:
+  - `swing=N` swing for just this track
+  - `accents=S,R,L` accent gains for just this track
+  - `vol=N` volume in dB, and `pan=N` -100 left to 100 right
+  - `mute=on` and `solo=on`
+  - `choke=N` puts it in choke group N, 1 to 4. tracks in the same group cut each other off
+  - effect knobs, see `Knob`: `cutoff=N` and `res=N` for the filter, `drive=N` for the
+    distortion, `delay=N` steps, `feedback=N` and `wet=N` for the delay, `reverb-send=N`
+    and `delay-send=N` to the buses. all percent but drive in dB. knobs that are missing
+    are where they start, out of the way
+- `song` is the arrangement for song mode, optional: which pattern and how many times round,
+  `<slot>x<repeats>`, in order
+- `song-loop <first> <last>` are the song entries it loops round, the whole song if missing
+- `song-end` is `loop` to go back round the loop at the end of the song, or `stop`
+- `pattern N` starts slot N of the bank, up to `BANK_SIZE`. it has one `hits` line per
+  track, in track order, with one char per step: `_` silent, `-` soft, `+` regular,
+  `#` loud. they set the velocity to the level's preset, 48, 96 and 127. tracks can be
+  different lengths, in every pattern
+- `step <track> <step> key=value ...` sets more about one step of the pattern its in,
+  after the `hits` lines:
+  - `vel=N` velocity, 1 to 127, when its not the preset. the hit char is the level its in
+  - `prob=N` percent chance the step plays
+  - `cond=C` when it plays: `1st` or `!1st` time round, `N:M` the Nth time round out of
+    every M, `fill` or `!fill`, `pre` or `!pre` if the last conditional step on the
+    track did or didnt play
+  - `ratchet=N` plays 2, 3 or 4 hits spread evenly over the step
+  - `decay=on` makes every hit of the ratchet quieter than the one before
+  - `nudge=N` plays it N 24ths of a step late, or early if its negative, up to half a step
+  - `pitch=N`, `gain=N`, `pan=N` and `start=N` lock the sound: semitones, dB, -100 left to
+    100 right, and percent of the way into the sample it starts
+- `seed` is where the dice start for steps that might not play, so renders come out the
+  same every time. its optional, 0 if missing
+- `velocity-curve` is how loud velocities are, `velocity:dB` points with straight lines
+  between them. its optional, the default has the presets at -6, 0 and 6 dB
+- `accents` is extra gain in dB for soft, regular and loud hits, on top of the curve. its
+  optional, all 0 if missing
+- `buses` has the send bus knobs that have been turned: `reverb-size=N`,
+  `reverb-damping=N`, `bus-delay=N` steps and `bus-feedback=N`. its optional
+- slots that arent in the file are silent, with the track lengths of the first one that is
+- blank lines and lines starting with `#` are ignored
+*/
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::effects::{Effects, Knob};
+use crate::mixer::{ChannelStrip, MAX_PAN, VOLUME_RANGE};
+use crate::playback::{CHOKE_GROUPS, MAX_SWING};
+use crate::samples::ActiveSamples;
+use crate::sequencer::{
+    AccentLevel, Lock, SampleSequence, Sequence, Step, MAX_NUDGE, MAX_RATCHET, MAX_VELOCITY,
+};
+use crate::song::{Song, SongEntry};
+use crate::velocity::{AccentGains, VelocityCurve};
+
+/// current version of the pattern format
+pub const FORMAT_VERSION: u32 = 1;
+
+/// how many patterns fit in the bank
+pub const BANK_SIZE: usize = 16;
+
+static MAGIC: &str = "fungus-pattern";
+
+/// a `step` line: line number, track, step and its settings
+type StepLine<'a> = (usize, usize, usize, Vec<&'a str>);
+
+/// Everything needed to get a beat back after fungus exits
+pub struct Pattern {
+    pub tempo: u32,
+    pub divisions: u32,
+    /// every pattern, by slot. they all have the same tracks
+    pub bank: Vec<SampleSequence>,
+    /// the slot that plays first
+    pub play: usize,
+    /// the kit the samples come out of, None for the library's first one
+    pub kit: Option<String>,
+    /// which sample each track plays, by index into the sample bank
+    pub samples: Vec<usize>,
+    /// percent of a step every second step is late
+    pub swing: u32,
+    /// per track swing, None for the global one
+    pub track_swing: Vec<Option<u32>>,
+    /// the arrangement for song mode
+    pub song: Song,
+    /// where the dice start for steps that might not play
+    pub seed: u64,
+    /// how loud each velocity is
+    pub curve: VelocityCurve,
+    /// extra gain for each accent level
+    pub accents: AccentGains,
+    /// per track accent gains, None for the global ones
+    pub track_accents: Vec<Option<AccentGains>>,
+    /// every track's channel strip
+    pub mix: Vec<ChannelStrip>,
+    /// every track's effects
+    pub effects: Vec<Effects>,
+    /// the send buses' effects
+    pub bus_effects: Effects,
+    /// every track's choke group, None for tracks that dont choke
+    pub choke: Vec<Option<u8>>,
+}
+
+impl Pattern {
+    /// new bank of empty patterns with one track per sample
+    ///
+    /// num_samples: how many samples are in the bank
+    pub fn new(num_samples: usize, steps: usize, divisions: u32, tempo: u32) -> Pattern {
+        Pattern {
+            tempo,
+            divisions,
+            bank: (0..BANK_SIZE)
+                .map(|_| SampleSequence::new(num_samples, steps))
+                .collect(),
+            play: 0,
+            kit: None,
+            samples: (0..num_samples).collect(),
+            swing: 0,
+            track_swing: vec![None; num_samples],
+            song: Song::default(),
+            seed: 0,
+            curve: VelocityCurve::default(),
+            accents: AccentGains::default(),
+            track_accents: vec![None; num_samples],
+            mix: vec![ChannelStrip::default(); num_samples],
+            effects: vec![Effects::default(); num_samples],
+            bus_effects: Effects::default(),
+            choke: vec![None; num_samples],
+        }
+    }
+
+    /// the pattern that plays first
+    pub fn sequence(&self) -> &SampleSequence {
+        &self.bank[self.play]
+    }
+
+    /// the pattern that plays first, for editing
+    pub fn sequence_mut(&mut self) -> &mut SampleSequence {
+        &mut self.bank[self.play]
+    }
+
+    /// the swing a track plays with, its own or the global one
+    pub fn track_swing(&self, track: usize) -> u32 {
+        self.track_swing
+            .get(track)
+            .copied()
+            .flatten()
+            .unwrap_or(self.swing)
+    }
+
+    /// read a pattern from a file
+    ///
+    /// returns an error if the file cant be read or isnt a valid pattern
+    pub fn load(path: &Path) -> Result<Pattern, Box<dyn Error>> {
+        Pattern::parse(&fs::read_to_string(path)?)
+    }
+
+    /// write the pattern to a file, overwriting it
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, self.serialize())?;
+        Ok(())
+    }
+
+    /// Check that this pattern can be played with a sample bank
+    ///
+    /// num_samples: how many samples are in the bank
+    ///
+    /// returns an error describing the mismatch if it cant
+    pub fn validate(&self, num_samples: usize) -> Result<(), Box<dyn Error>> {
+        if self.samples.len() != num_samples {
+            return Err(format!(
+                "pattern has {} tracks but the sample bank has {} samples",
+                self.samples.len(),
+                num_samples
+            )
+            .into());
+        }
+        if let Some(s) = self.samples.iter().find(|&&s| s >= num_samples) {
+            return Err(format!(
+                "pattern uses sample {} but the sample bank only has {} samples",
+                s, num_samples
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// pick the sample each track plays out of the bank, in track order
+    ///
+    /// call validate first, this panics if the pattern uses samples the bank doesnt have
+    pub fn track_samples(&self, bank: &ActiveSamples) -> ActiveSamples {
+        self.samples.iter().map(|&s| bank[s].clone()).collect()
+    }
+
+    /// the pattern in the file format
+    pub fn serialize(&self) -> String {
+        let mut out = format!(
+            "{} {}\ntempo {}\ndivisions {}\nswing {}\nplay {}\n",
+            MAGIC, FORMAT_VERSION, self.tempo, self.divisions, self.swing, self.play,
+        );
+        if let Some(kit) = &self.kit {
+            out.push_str(&format!("kit {}\n", kit));
+        }
+
+        for (i, sample) in self.samples.iter().enumerate() {
+            out.push_str(&format!("track {}", sample));
+            if let Some(Some(group)) = self.choke.get(i) {
+                out.push_str(&format!(" choke={}", group));
+            }
+            if let Some(Some(swing)) = self.track_swing.get(i) {
+                out.push_str(&format!(" swing={}", swing));
+            }
+            if let Some(Some(accents)) = self.track_accents.get(i) {
+                out.push_str(&format!(" accents={}", accents));
+            }
+            let strip = self.mix.get(i).copied().unwrap_or_default();
+            if strip.volume != 0.0 {
+                out.push_str(&format!(" vol={}", strip.volume));
+            }
+            if strip.pan != 0 {
+                out.push_str(&format!(" pan={}", strip.pan));
+            }
+            if strip.mute {
+                out.push_str(" mute=on");
+            }
+            if strip.solo {
+                out.push_str(" solo=on");
+            }
+            let fx = self.effects.get(i).copied().unwrap_or_default();
+            for (knob, value) in fx.changed(false) {
+                out.push_str(&format!(" {}={}", knob, value));
+            }
+            out.push('\n');
+        }
+        if !self.song.entries.is_empty() {
+            let entries: Vec<String> = self
+                .song
+                .entries
+                .iter()
+                .map(|e| format!("{}x{}", e.pattern, e.repeats))
+                .collect();
+            out.push_str(&format!("song {}\n", entries.join(" ")));
+            if let Some((first, last)) = self.song.loop_points {
+                out.push_str(&format!("song-loop {} {}\n", first, last));
+            }
+            let end = if self.song.stop_at_end {
+                "stop"
+            } else {
+                "loop"
+            };
+            out.push_str(&format!("song-end {}\n", end));
+        }
+        out.push_str(&format!("seed {}\n", self.seed));
+        out.push_str(&format!("velocity-curve {}\n", self.curve));
+        out.push_str(&format!("accents {}\n", self.accents));
+        let buses = self.bus_effects.changed(true);
+        if !buses.is_empty() {
+            let knobs: Vec<String> = buses.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            out.push_str(&format!("buses {}\n", knobs.join(" ")));
+        }
+        for (slot, sequence) in self.bank.iter().enumerate() {
+            out.push_str(&format!("pattern {}\n", slot));
+            let tracks = sequence.tracks();
+            for track in &tracks {
+                let steps: String = track.iter().map(|s| step_char(s.level())).collect();
+                out.push_str(&format!("hits {}\n", steps));
+            }
+            for (t, track) in tracks.iter().enumerate() {
+                for (i, step) in track.iter().enumerate() {
+                    let settings = step_settings(step);
+                    if !settings.is_empty() {
+                        out.push_str(&format!("step {} {} {}\n", t, i, settings.join(" ")));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// read a pattern from the file format
+    ///
+    /// returns an error with the offending line number if its malformed
+    pub fn parse(text: &str) -> Result<Pattern, Box<dyn Error>> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(i, l)| (i + 1, l.trim()))
+            .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'));
+
+        match lines.next() {
+            Some((_, header)) => {
+                let mut words = header.split_whitespace();
+                if words.next() != Some(MAGIC) {
+                    return Err("not a fungus pattern file".into());
+                }
+                let version: u32 = words
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or("pattern file has no format version")?;
+                if version != FORMAT_VERSION {
+                    return Err(format!("unsupported pattern format version {}", version).into());
+                }
+            }
+            None => return Err("empty pattern file".into()),
+        }
+
+        let mut tempo = None;
+        let mut divisions = None;
+        let mut swing = 0;
+        let mut play = 0;
+        let mut kit = None;
+        let mut samples = vec![];
+        let mut track_swing = vec![];
+        // hits of every track, by slot
+        let mut slots: Vec<Option<Vec<Vec<AccentLevel>>>> = vec![None; BANK_SIZE];
+        let mut slot = None;
+        // step settings by slot
+        let mut step_lines: Vec<Vec<StepLine>> = vec![vec![]; BANK_SIZE];
+        let mut seed = 0;
+        let mut curve = VelocityCurve::default();
+        let mut accents = AccentGains::default();
+        let mut track_accents = vec![];
+        let mut mix = vec![];
+        let mut effects = vec![];
+        let mut bus_effects = Effects::default();
+        let mut choke = vec![];
+        let mut song = Song::default();
+
+        for (num, line) in lines {
+            let (key, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let value = value.trim();
+            match key {
+                "tempo" => tempo = Some(parse_num(value, num)?),
+                "divisions" => divisions = Some(parse_num(value, num)?),
+                "swing" => swing = parse_swing(value, num)?,
+                "play" => play = parse_slot(value, num)?,
+                "kit" if !value.is_empty() => kit = Some(value.to_string()),
+                "track" => {
+                    let mut words = value.split_whitespace();
+                    let sample = words
+                        .next()
+                        .ok_or(format!("line {}: track needs a sample", num))?;
+                    samples.push(parse_num(sample, num)?);
+                    let mut own_swing = None;
+                    let mut own_accents = None;
+                    let mut strip = ChannelStrip::default();
+                    let mut fx = Effects::default();
+                    let mut group = None;
+                    for setting in words {
+                        match setting.split_once('=') {
+                            Some(("swing", s)) => own_swing = Some(parse_swing(s, num)?),
+                            Some(("accents", a)) => {
+                                own_accents =
+                                    Some(a.parse().map_err(|e| format!("line {}: {}", num, e))?)
+                            }
+                            Some(("vol", v)) => {
+                                strip.volume = match v.parse::<f64>() {
+                                    Ok(v) if (VOLUME_RANGE.0..=VOLUME_RANGE.1).contains(&v) => v,
+                                    _ => {
+                                        return Err(
+                                            format!("line {}: vol is -60 to 12 dB", num).into()
+                                        )
+                                    }
+                                }
+                            }
+                            Some(("pan", p)) => {
+                                strip.pan = match p.parse::<i8>() {
+                                    Ok(p) if (-MAX_PAN..=MAX_PAN).contains(&p) => p,
+                                    _ => {
+                                        return Err(
+                                            format!("line {}: pan is -100 to 100", num).into()
+                                        )
+                                    }
+                                }
+                            }
+                            Some(("mute", "on")) => strip.mute = true,
+                            Some(("solo", "on")) => strip.solo = true,
+                            Some(("choke", g)) => {
+                                group = match g.parse::<u8>() {
+                                    Ok(g) if (1..=CHOKE_GROUPS).contains(&g) => Some(g),
+                                    _ => {
+                                        return Err(format!(
+                                            "line {}: choke groups go from 1 to {}",
+                                            num, CHOKE_GROUPS
+                                        )
+                                        .into())
+                                    }
+                                }
+                            }
+                            Some((key, v)) if key.parse::<Knob>().is_ok_and(|k| !k.is_bus()) => {
+                                parse_knob(&mut fx, key, v, num)?
+                            }
+                            _ => {
+                                return Err(
+                                    format!("line {}: unknown setting '{}'", num, setting).into()
+                                )
+                            }
+                        }
+                    }
+                    track_swing.push(own_swing);
+                    track_accents.push(own_accents);
+                    mix.push(strip);
+                    effects.push(fx);
+                    choke.push(group);
+                }
+                "song" => {
+                    for entry in value.split_whitespace() {
+                        let (pattern, repeats) = entry.split_once('x').ok_or(format!(
+                            "line {}: song entries are <pattern>x<repeats>",
+                            num
+                        ))?;
+                        let repeats = parse_num(repeats, num)?;
+                        if repeats == 0 {
+                            return Err(
+                                format!("line {}: a song entry plays at least once", num).into()
+                            );
+                        }
+                        song.entries.push(SongEntry {
+                            pattern: parse_slot(pattern, num)?,
+                            repeats,
+                        });
+                    }
+                }
+                "song-loop" => {
+                    let (first, last) = value.split_once(char::is_whitespace).ok_or(format!(
+                        "line {}: song-loop needs a first and last entry",
+                        num
+                    ))?;
+                    song.loop_points = Some((parse_num(first, num)?, parse_num(last, num)?));
+                }
+                "song-end" => {
+                    song.stop_at_end = match value {
+                        "stop" => true,
+                        "loop" => false,
+                        _ => return Err(format!("line {}: song-end is stop or loop", num).into()),
+                    }
+                }
+                "pattern" => {
+                    let s = parse_slot(value, num)?;
+                    if slots[s].is_some() {
+                        return Err(format!("line {}: pattern {} is in here twice", num, s).into());
+                    }
+                    slots[s] = Some(vec![]);
+                    slot = Some(s);
+                }
+                "hits" => {
+                    let s = slot.ok_or(format!("line {}: hits before any pattern", num))?;
+                    let hits = parse_hits(value, num)?;
+                    slots[s].get_or_insert_with(Vec::new).push(hits);
+                }
+                "step" => {
+                    let s = slot.ok_or(format!("line {}: step before any pattern", num))?;
+                    let mut words = value.split_whitespace();
+                    let (track, step) = words
+                        .next()
+                        .zip(words.next())
+                        .ok_or(format!("line {}: step needs a track and a step", num))?;
+                    step_lines[s].push((
+                        num,
+                        parse_num(track, num)?,
+                        parse_num(step, num)?,
+                        words.collect(),
+                    ));
+                }
+                "seed" => seed = parse_num(value, num)?,
+                "accents" => accents = value.parse().map_err(|e| format!("line {}: {}", num, e))?,
+                "buses" => {
+                    for setting in value.split_whitespace() {
+                        match setting.split_once('=') {
+                            Some((key, v)) if key.parse::<Knob>().is_ok_and(|k| k.is_bus()) => {
+                                parse_knob(&mut bus_effects, key, v, num)?
+                            }
+                            _ => {
+                                return Err(format!(
+                                    "line {}: unknown bus setting '{}'",
+                                    num, setting
+                                )
+                                .into())
+                            }
+                        }
+                    }
+                }
+                "velocity-curve" => {
+                    curve = value.parse().map_err(|e| format!("line {}: {}", num, e))?
+                }
+                _ => return Err(format!("line {}: unknown key '{}'", num, key).into()),
+            }
+        }
+
+        let tempo = tempo.ok_or("pattern file is missing tempo")?;
+        let divisions = divisions.ok_or("pattern file is missing divisions")?;
+        if tempo == 0 || divisions == 0 {
+            return Err("tempo and divisions must be at least 1".into());
+        }
+
+        if let Some((first, last)) = song.loop_points {
+            if first > last || last >= song.entries.len() {
+                return Err("song loop points are outside the song".into());
+            }
+        }
+
+        // empty slots get the track lengths of the first pattern in the file
+        let lengths: Vec<usize> = match slots.iter().flatten().next() {
+            Some(tracks) => tracks.iter().map(|t| t.len()).collect(),
+            None => vec![1; samples.len()],
+        };
+        let mut bank = vec![];
+        for (s, (tracks, step_lines)) in slots.into_iter().zip(step_lines).enumerate() {
+            let tracks = tracks.unwrap_or_else(|| {
+                lengths
+                    .iter()
+                    .map(|&l| vec![AccentLevel::Silent; l])
+                    .collect()
+            });
+            if tracks.len() != samples.len() {
+                return Err(format!(
+                    "pattern {} has {} tracks, the file has {}",
+                    s,
+                    tracks.len(),
+                    samples.len()
+                )
+                .into());
+            }
+            let mut sequence = SampleSequence::new(tracks.len(), 0);
+            for (i, hits) in tracks.into_iter().enumerate() {
+                sequence
+                    .set_track_steps(i, hits.len())
+                    .map_err(|e| format!("pattern {} track {}: {}", s, i, e))?;
+                for (step, hit) in hits.into_iter().enumerate() {
+                    sequence.set_step(i, step, hit)?;
+                }
+            }
+            for (num, track, step, settings) in step_lines {
+                let mut step_data = sequence.get_step(track, step).ok_or(format!(
+                    "line {}: there is no step {} on track {}",
+                    num, step, track
+                ))?;
+                for setting in settings {
+                    parse_step_setting(&mut step_data, setting, num)?;
+                }
+                sequence.put_step(track, step, step_data)?;
+            }
+            bank.push(sequence);
+        }
+
+        Ok(Pattern {
+            tempo,
+            divisions,
+            bank,
+            play,
+            kit,
+            samples,
+            swing,
+            track_swing,
+            song,
+            seed,
+            curve,
+            accents,
+            track_accents,
+            mix,
+            effects,
+            bus_effects,
+            choke,
+        })
+    }
+}
+
+fn parse_hits(s: &str, line: usize) -> Result<Vec<AccentLevel>, String> {
+    s.chars()
+        .map(|c| char_step(c).ok_or(format!("line {}: bad step '{}'", line, c)))
+        .collect()
+}
+
+/// the `key=value` settings of a step that arent the defaults
+fn step_settings(step: &Step) -> Vec<String> {
+    let default = Step::default();
+    let mut settings = vec![];
+    if step.velocity != step.level().velocity() {
+        settings.push(format!("vel={}", step.velocity));
+    }
+    if step.probability != default.probability {
+        settings.push(format!("prob={}", step.probability));
+    }
+    if step.condition != default.condition {
+        settings.push(format!("cond={}", step.condition));
+    }
+    if step.ratchet != default.ratchet {
+        settings.push(format!("ratchet={}", step.ratchet));
+    }
+    if step.decay {
+        settings.push("decay=on".to_string());
+    }
+    if step.nudge != default.nudge {
+        settings.push(format!("nudge={}", step.nudge));
+    }
+    for lock in Lock::all() {
+        if let Some(value) = step.locks.get(lock) {
+            settings.push(format!("{}={}", lock, value));
+        }
+    }
+    settings
+}
+
+fn parse_step_setting(step: &mut Step, setting: &str, line: usize) -> Result<(), String> {
+    match setting.split_once('=') {
+        Some(("vel", v)) => {
+            step.velocity = parse_num(v, line)?;
+            if !(1..=MAX_VELOCITY).contains(&step.velocity) {
+                return Err(format!(
+                    "line {}: velocity goes from 1 to {}",
+                    line, MAX_VELOCITY
+                ));
+            }
+        }
+        Some(("prob", p)) => {
+            step.probability = parse_num(p, line)?;
+            if step.probability > 100 {
+                return Err(format!("line {}: probability is a percent, 0 to 100", line));
+            }
+        }
+        Some(("cond", c)) => {
+            step.condition = c.parse().map_err(|e| format!("line {}: {}", line, e))?;
+        }
+        Some(("ratchet", r)) => {
+            step.ratchet = parse_num(r, line)?;
+            if !(1..=MAX_RATCHET).contains(&step.ratchet) {
+                return Err(format!(
+                    "line {}: ratchets are 1 to {} hits",
+                    line, MAX_RATCHET
+                ));
+            }
+        }
+        Some(("nudge", n)) => {
+            step.nudge = parse_num(n, line)?;
+            if !(-MAX_NUDGE..=MAX_NUDGE).contains(&step.nudge) {
+                return Err(format!(
+                    "line {}: nudges go from -{} to {}",
+                    line, MAX_NUDGE, MAX_NUDGE
+                ));
+            }
+        }
+        Some(("decay", d)) => {
+            step.decay = match d {
+                "on" => true,
+                "off" => false,
+                _ => return Err(format!("line {}: decay is on or off", line)),
+            }
+        }
+        // sound locks, pitch=N and the like
+        Some((key, v)) if key.parse::<Lock>().is_ok() => {
+            let lock: Lock = key.parse()?;
+            let (low, high) = lock.range();
+            step.locks
+                .set(lock, Some(parse_num(v, line)?))
+                .map_err(|_| format!("line {}: {} goes from {} to {}", line, lock, low, high))?;
+        }
+        _ => return Err(format!("line {}: unknown step setting '{}'", line, setting)),
+    }
+    Ok(())
+}
+
+/// turn a knob to a `key=value` setting
+fn parse_knob(fx: &mut Effects, key: &str, value: &str, line: usize) -> Result<(), String> {
+    let knob: Knob = key.parse().map_err(|e| format!("line {}: {}", line, e))?;
+    let (low, high) = knob.range();
+    let value = value
+        .parse()
+        .map_err(|_| format!("line {}: {} goes from {} to {}", line, knob, low, high))?;
+    fx.set(knob, value)
+        .map_err(|_| format!("line {}: {} goes from {} to {}", line, knob, low, high))
+}
+
+fn parse_slot(s: &str, line: usize) -> Result<usize, String> {
+    let slot = parse_num(s, line)?;
+    if slot >= BANK_SIZE {
+        return Err(format!(
+            "line {}: there are only {} patterns",
+            line, BANK_SIZE
+        ));
+    }
+    Ok(slot)
+}
+
+fn parse_num<T: std::str::FromStr>(s: &str, line: usize) -> Result<T, String> {
+    s.trim()
+        .parse()
+        .map_err(|_| format!("line {}: '{}' is not a number", line, s))
+}
+
+fn parse_swing(s: &str, line: usize) -> Result<u32, String> {
+    let swing = parse_num(s, line)?;
+    if swing > MAX_SWING {
+        return Err(format!("line {}: swing can be at most {}", line, MAX_SWING));
+    }
+    Ok(swing)
+}
+
+fn step_char(step: AccentLevel) -> char {
+    match step {
+        AccentLevel::Loud => '#',
+        AccentLevel::Regular => '+',
+        AccentLevel::Soft => '-',
+        AccentLevel::Silent => '_',
+    }
+}
+
+fn char_step(c: char) -> Option<AccentLevel> {
+    match c {
+        '#' => Some(AccentLevel::Loud),
+        '+' => Some(AccentLevel::Regular),
+        '-' => Some(AccentLevel::Soft),
+        '_' => Some(AccentLevel::Silent),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pattern;
+    use crate::effects::Knob;
+    use crate::sequencer::{AccentLevel, Sequence};
+    use crate::sequencer::{Condition, Locks, Step};
+    use crate::song::SongEntry;
+
+    /// the lines every file needs, for the malformed ones
+    static HEADER: &str = "fungus-pattern 1\ntempo 1\ndivisions 1\n";
+
+    #[test]
+    fn round_trip() {
+        let mut p = Pattern::new(3, 8, 4, 97);
+        p.samples = vec![2, 0, 1];
+        p.sequence_mut().set_step(0, 0, AccentLevel::Loud).unwrap();
+        p.sequence_mut().set_step(1, 3, AccentLevel::Soft).unwrap();
+        p.sequence_mut()
+            .set_step(2, 7, AccentLevel::Regular)
+            .unwrap();
+        p.curve = "1:-40 127:0".parse().unwrap();
+
+        let back = Pattern::parse(&p.serialize()).unwrap();
+        assert_eq!(back.curve, p.curve);
+        assert_eq!(back.tempo, 97);
+        assert_eq!(back.divisions, 4);
+        assert_eq!(back.samples, vec![2, 0, 1]);
+        assert_eq!(back.sequence().steps(), 8);
+        assert_eq!(back.sequence().tracks(), p.sequence().tracks());
+    }
+
+    #[test]
+    fn ragged_tracks() {
+        let mut p = Pattern::new(2, 16, 4, 120);
+        p.sequence_mut().set_track_steps(1, 12).unwrap();
+        p.sequence_mut().set_step(1, 11, AccentLevel::Loud).unwrap();
+
+        let back = Pattern::parse(&p.serialize()).unwrap();
+        assert_eq!(back.sequence().track_steps(0), 16);
+        assert_eq!(back.sequence().track_steps(1), 12);
+        assert_eq!(back.sequence().tracks()[1][11], AccentLevel::Loud);
+
+        // empty tracks dont make sense
+        assert!(Pattern::parse(&format!("{}track 0\npattern 0\nhits ", HEADER)).is_err());
+    }
+
+    #[test]
+    fn bank() {
+        let mut p = Pattern::new(2, 4, 4, 120);
+        p.bank[3].set_track_steps(0, 6).unwrap();
+        p.bank[3].set_step(0, 5, AccentLevel::Loud).unwrap();
+        p.play = 3;
+
+        let back = Pattern::parse(&p.serialize()).unwrap();
+        assert_eq!(back.bank.len(), super::BANK_SIZE);
+        assert_eq!(back.play, 3);
+        assert_eq!(back.sequence().tracks(), p.bank[3].tracks());
+        assert_eq!(back.bank[0].tracks(), p.bank[0].tracks());
+
+        // missing slots are silent with the first pattern's lengths
+        let two = format!("{}track 0\ntrack 1\n", HEADER);
+        let p = Pattern::parse(&format!("{}pattern 2\nhits +_+\nhits #_", two)).unwrap();
+        assert_eq!(p.bank[2].tracks()[1][0], AccentLevel::Loud);
+        // hits get the preset velocity of their level
+        assert_eq!(p.bank[2].tracks()[1][0].velocity, 127);
+        assert_eq!(p.bank[0].track_steps(0), 3);
+        assert_eq!(p.bank[0].track_steps(1), 2);
+
+        // every pattern needs a hits line per track, once
+        assert!(Pattern::parse(&format!("{}pattern 0\nhits +_+", two)).is_err());
+        assert!(Pattern::parse(&format!("{}hits +_+\nhits +", two)).is_err());
+        let twice = format!(
+            "{}pattern 0\nhits +\nhits +\npattern 0\nhits +\nhits +",
+            two
+        );
+        assert!(Pattern::parse(&twice).is_err());
+        assert!(Pattern::parse(&format!("{}pattern 16", two)).is_err());
+    }
+
+    #[test]
+    fn song() {
+        let mut p = Pattern::new(1, 4, 4, 120);
+        p.song.entries = vec![
+            SongEntry {
+                pattern: 0,
+                repeats: 2,
+            },
+            SongEntry {
+                pattern: 3,
+                repeats: 1,
+            },
+            SongEntry {
+                pattern: 1,
+                repeats: 4,
+            },
+        ];
+        p.song.loop_points = Some((1, 2));
+        p.song.stop_at_end = true;
+
+        let back = Pattern::parse(&p.serialize()).unwrap();
+        assert_eq!(back.song, p.song);
+        // no song, no song lines
+        assert!(!Pattern::new(1, 4, 4, 120).serialize().contains("song"));
+
+        let one = format!("{}track 0\npattern 0\nhits +\n", HEADER);
+        assert!(Pattern::parse(&format!("{}song 0x0", one)).is_err());
+        assert!(Pattern::parse(&format!("{}song 16x1", one)).is_err());
+        assert!(Pattern::parse(&format!("{}song 0-1", one)).is_err());
+        assert!(Pattern::parse(&format!("{}song 0x1 1x1\nsong-loop 1 2", one)).is_err());
+        assert!(Pattern::parse(&format!("{}song 0x1 1x1\nsong-loop 1 0", one)).is_err());
+        assert!(Pattern::parse(&format!("{}song-end maybe", one)).is_err());
+    }
+
+    #[test]
+    fn step_settings() {
+        let mut p = Pattern::new(2, 4, 4, 120);
+        p.seed = 1234;
+        p.bank[2].set_step(1, 3, AccentLevel::Soft).unwrap();
+        let step = Step {
+            velocity: 30,
+            probability: 25,
+            condition: Condition::Every { nth: 3, of: 4 },
+            ratchet: 3,
+            decay: true,
+            nudge: -5,
+            locks: Locks {
+                pitch: Some(12),
+                gain: Some(-6),
+                pan: Some(-100),
+                start: Some(0),
+            },
+        };
+        p.bank[2].put_step(1, 3, step).unwrap();
+        p.bank[0]
+            .put_step(
+                0,
+                0,
+                Step {
+                    probability: 0,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let back = Pattern::parse(&p.serialize()).unwrap();
+        assert_eq!(back.seed, 1234);
+        assert_eq!(back.bank[2].get_step(1, 3), Some(step));
+        assert_eq!(back.bank[0].get_step(0, 0).unwrap().probability, 0);
+        assert_eq!(back.bank[2].get_step(1, 2), Some(Step::default()));
+
+        let one = format!("{}track 0\npattern 0\nhits +_\n", HEADER);
+        assert!(Pattern::parse(&format!("{}step 0 1 cond=!fill prob=5", one)).is_ok());
+        assert!(Pattern::parse(&format!("{}step 0 2 prob=5", one)).is_err());
+        assert!(Pattern::parse(&format!("{}step 1 0 prob=5", one)).is_err());
+        assert!(Pattern::parse(&format!("{}step 0 0 prob=101", one)).is_err());
+        assert!(Pattern::parse(&format!("{}step 0 0 cond=often", one)).is_err());
+        assert!(Pattern::parse(&format!("{}step 0 0 loud=1", one)).is_err());
+        assert!(Pattern::parse(&format!("{}step 0 0 ratchet=4 decay=on", one)).is_ok());
+        assert!(Pattern::parse(&format!("{}step 0 0 ratchet=5", one)).is_err());
+        assert!(Pattern::parse(&format!("{}step 0 0 decay=yes", one)).is_err());
+        assert!(Pattern::parse(&format!("{}step 0 0 nudge=-12", one)).is_ok());
+        assert!(Pattern::parse(&format!("{}step 0 0 nudge=13", one)).is_err());
+        assert!(Pattern::parse(&format!("{}step 0 0 pitch=24 start=99", one)).is_ok());
+        assert!(Pattern::parse(&format!("{}step 0 0 pan=101", one)).is_err());
+        assert!(Pattern::parse(&format!("{}step 0 0 gain=loud", one)).is_err());
+        assert!(Pattern::parse(&format!("{}step 0 0 vel=1", one)).is_ok());
+        assert!(Pattern::parse(&format!("{}step 0 0 vel=128", one)).is_err());
+        assert!(Pattern::parse(&format!("{}step 0 0 vel=0", one)).is_err());
+    }
+
+    #[test]
+    fn swing() {
+        let mut p = Pattern::new(3, 4, 4, 120);
+        p.swing = 20;
+        p.track_swing[1] = Some(0);
+        p.track_swing[2] = Some(60);
+
+        let back = Pattern::parse(&p.serialize()).unwrap();
+        assert_eq!(back.swing, 20);
+        assert_eq!(back.track_swing, vec![None, Some(0), Some(60)]);
+        assert_eq!(back.track_swing(0), 20);
+        assert_eq!(back.track_swing(1), 0);
+
+        assert!(Pattern::parse(&format!("{}swing 76", HEADER)).is_err());
+        assert!(Pattern::parse(&format!("{}track 0 swing=99", HEADER)).is_err());
+        assert!(Pattern::parse(&format!("{}track 0 nope=1", HEADER)).is_err());
+    }
+
+    #[test]
+    fn accents() {
+        let mut p = Pattern::new(2, 4, 4, 120);
+        p.accents = "-6,0,6".parse().unwrap();
+        p.track_accents[1] = Some("-12,1.5,0".parse().unwrap());
+
+        let back = Pattern::parse(&p.serialize()).unwrap();
+        assert_eq!(back.accents, p.accents);
+        assert_eq!(back.track_accents, p.track_accents);
+
+        assert!(Pattern::parse(&format!("{}accents 0,0", HEADER)).is_err());
+        assert!(Pattern::parse(&format!("{}track 0 accents=0,0,20", HEADER)).is_err());
+    }
+
+    #[test]
+    fn mixer() {
+        let mut p = Pattern::new(3, 4, 4, 120);
+        p.mix[0].volume = -4.5;
+        p.mix[1].pan = -30;
+        p.mix[1].mute = true;
+        p.mix[2].solo = true;
+
+        let back = Pattern::parse(&p.serialize()).unwrap();
+        assert_eq!(back.mix, p.mix);
+
+        assert!(Pattern::parse(&format!("{}track 0 vol=13", HEADER)).is_err());
+        assert!(Pattern::parse(&format!("{}track 0 pan=-101", HEADER)).is_err());
+        assert!(Pattern::parse(&format!("{}track 0 mute=off", HEADER)).is_err());
+    }
+
+    #[test]
+    fn effects() {
+        let mut p = Pattern::new(2, 4, 4, 120);
+        p.effects[1].set(Knob::Cutoff, 40).unwrap();
+        p.effects[1].set(Knob::ReverbSend, 30).unwrap();
+        p.bus_effects.set(Knob::BusDelay, 4).unwrap();
+
+        let text = p.serialize();
+        assert!(
+            text.contains("track 1 cutoff=40 reverb-send=30\n"),
+            "{}",
+            text
+        );
+        let back = Pattern::parse(&text).unwrap();
+        assert_eq!(back.effects, p.effects);
+        assert_eq!(back.bus_effects, p.bus_effects);
+
+        assert!(Pattern::parse(&format!("{}track 0 drive=49", HEADER)).is_err());
+        assert!(Pattern::parse(&format!("{}track 0 bus-delay=2", HEADER)).is_err());
+        assert!(Pattern::parse(&format!("{}buses cutoff=2", HEADER)).is_err());
+    }
+
+    #[test]
+    fn choke_groups() {
+        let mut p = Pattern::new(3, 4, 4, 120);
+        p.choke = vec![Some(2), None, Some(2)];
+        let back = Pattern::parse(&p.serialize()).unwrap();
+        assert_eq!(back.choke, p.choke);
+
+        assert!(Pattern::parse(&format!("{}track 0 choke=0", HEADER)).is_err());
+        assert!(Pattern::parse(&format!("{}track 0 choke=5", HEADER)).is_err());
+    }
+
+    #[test]
+    fn kits() {
+        let mut p = Pattern::new(1, 4, 4, 120);
+        assert_eq!(Pattern::parse(&p.serialize()).unwrap().kit, None);
+        p.kit = Some("old 808".to_string());
+        let back = Pattern::parse(&p.serialize()).unwrap();
+        assert_eq!(back.kit, p.kit);
+    }
+
+    #[test]
+    fn bad_files() {
+        assert!(Pattern::parse("").is_err());
+        assert!(Pattern::parse("not-a-pattern 1").is_err());
+        assert!(Pattern::parse("fungus-pattern 2\ntempo 1\ndivisions 1").is_err());
+        assert!(Pattern::parse("fungus-pattern 0\ntempo 1\ndivisions 1").is_err());
+        assert!(Pattern::parse(HEADER).is_ok());
+        // missing divisions
+        assert!(Pattern::parse("fungus-pattern 1\ntempo 120").is_err());
+        assert!(Pattern::parse(&format!("{}steps 4", HEADER)).is_err());
+        // bad step char
+        let bad = format!("{}track 0\npattern 0\nhits +_x_", HEADER);
+        assert!(Pattern::parse(&bad).is_err());
+    }
+
+    #[test]
+    fn validating() {
+        let mut p = Pattern::new(2, 4, 4, 120);
+        assert!(p.validate(2).is_ok());
+        assert!(p.validate(3).is_err());
+        p.samples = vec![0, 5];
+        assert!(p.validate(2).is_err());
+    }
+}
@@ -1,33 +1,407 @@
 /**
 This module manages the playback thread
+
+Steps are timed by a kira clock that runs on the audio thread, `TICKS_PER_STEP` ticks to a
+step so hits can land between steps, eg: for swing. The playback loop only has to stay a
+little ahead of the clock and queue up the hits for the coming ticks, kira starts them on
+the exact sample the tick lands on.
+
+It keeps the whole pattern bank, edits to the playing pattern are heard straight away and
+a cued pattern takes over when the playing one wraps around. In song mode the song does
+the cueing, every time the playing pattern wraps.
+
+Every track gets a channel strip in the mixer with its volume and pan, muted tracks get
+turned all the way down. Velocity sets how loud a hit is through a curve, the accent level
+it falls in picks which of its track's accent mixer tracks it goes through on the way to the
+strip. Those have the track's accent gains. Changing anything in the mixer fades to it so
+theres no clicks. The strips have the track's insert effects ahead of the volume and pan,
+and send to the reverb and delay buses. On top of that every hit gets the sound settings its
+step has locked: pitch, gain, pan and where in the sample it starts. A ratchet queues all of
+its hits along with the step, spaced out over the step. Steps get queued far enough ahead
+that a step nudged early can still play before its tick.
+
+The samples can be swapped while it plays, eg: for another kit or when one gets edited.
+Tracks that move take their channel strips with them and the ones that go fade out, so
+nothing clicks. The new samples play from the next step that gets queued.
+
+Tracks in the same choke group cut each other off, eg: a closed hat stops a ringing open
+one. Every hit on them keeps its sound's handle, when another hit in the group comes along
+the sounds that started before it fade out on its tick.
+
+Where the clock is in the playing pattern goes back to the control loop every time it gets to
+another step, along with how many steps got dropped from falling behind. Hits played in live
+while recording find where the playhead is on the clock too, the step nearest them and how
+far off it they were. The step is the swung one, so a late hit on a swung step still lands on
+it. A hit recorded onto a step thats still to come has been heard already, so that step sits
+out till the next time round.
+
+Steps with a chance of playing or a trig condition get decided as they're queued. The dice
+come from a seeded generator so a render comes out the same every time.
 */
-use kira::manager::{backend::cpal::CpalBackend, AudioManager, AudioManagerSettings};
-use kira::sound::static_sound::StaticSoundSettings;
-use kira::track::{TrackBuilder, TrackHandle};
+use kira::clock::{ClockHandle, ClockSpeed, ClockTime};
+use kira::dsp::Frame;
+use kira::manager::backend::{cpal::CpalBackend, mock::MockBackend, Backend};
+use kira::manager::{AudioManager, AudioManagerSettings, Capacities};
+use kira::sound::static_sound::{StaticSoundHandle, StaticSoundSettings};
+use kira::sound::{PlaybackPosition, PlaybackRate, PlaybackState};
+use kira::track::effect::delay::{DelayBuilder, DelayHandle};
+use kira::track::effect::distortion::{DistortionBuilder, DistortionHandle, DistortionKind};
+use kira::track::effect::filter::{FilterBuilder, FilterHandle};
+use kira::track::effect::panning_control::{PanningControlBuilder, PanningControlHandle};
+use kira::track::effect::reverb::{ReverbBuilder, ReverbHandle};
+use kira::track::{TrackBuilder, TrackHandle, TrackRoutes};
+use kira::tween::Tween;
+use kira::StartTime;
 use kira::Volume;
-use std::time::Duration;
-use std::{error::Error, sync::mpsc::Receiver};
-
-use spin_sleep;
+use std::error::Error;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::samples::ActiveSamples;
-use crate::sequencer::{AccentLevel, SampleSequence, Sequence};
+use crate::effects::{self, Effects, Knob, MAX_DELAY_SECONDS};
+use crate::mixer::{self, ChannelStrip};
+use crate::samples::{self, ActiveSamples};
+use crate::sequencer::{
+    AccentLevel, Condition, Locks, SampleSequence, Sequence, Step, Track, MAX_NUDGE,
+    NUDGES_PER_STEP,
+};
+use crate::song::Song;
+use crate::velocity::{AccentGains, VelocityCurve};
 
 /// controls for playback
 pub struct Controls {
     pub tempo: u32,
+    /// how many steps to a beat
+    pub divisions: u32,
     pub mute: bool,
+    /// how far every second step gets pushed back, in percent of a step
+    pub swing: u32,
+    /// per track swing, None for the global one
+    pub track_swing: Vec<Option<u32>>,
+    /// slot in the bank to play, switched to when the playing one wraps around
+    pub pattern: usize,
+    /// the song to play instead of just the one pattern, None for pattern mode
+    pub song: Option<Song>,
+    /// fill is on, for the fill trig conditions
+    pub fill: bool,
+    /// how loud each velocity is
+    pub curve: VelocityCurve,
+    /// extra gain for each accent level
+    pub accents: AccentGains,
+    /// per track accent gains, None for the global ones
+    pub track_accents: Vec<Option<AccentGains>>,
+    /// every track's channel strip
+    pub mix: Vec<ChannelStrip>,
+    /// every track's effects
+    pub effects: Vec<Effects>,
+    /// the send buses' effects
+    pub bus_effects: Effects,
+    /// every track's choke group, None for tracks that dont choke
+    pub choke: Vec<Option<u8>>,
+}
+
+/// a hit played in live while recording, where it landed in the pattern thats playing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Recorded {
+    /// the slot in the bank thats playing
+    pub slot: usize,
+    pub track: usize,
+    /// the track's step nearest to the hit
+    pub step: usize,
+    /// how many times round its track has been
+    pub pass: u64,
+    /// how many ticks after the step it came, negative for early
+    pub offset: i64,
+    pub level: AccentLevel,
+}
+
+/// where playback is, sent back every time the clock gets to another step
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Playhead {
+    /// the slot in the bank thats playing
+    pub slot: usize,
+    /// steps since the playing pattern started, tracks wrap around from here on their own
+    pub position: u64,
+    /// the step of the pattern its on
+    pub step: usize,
+    /// the bar of the pattern its in, from 0
+    pub bar: usize,
+    /// how many times round the pattern has been
+    pub loops: u64,
+    /// how many steps got dropped since it started because playback fell behind the clock
+    pub dropped: u64,
+}
+
+/// news from the playback thread for the control loop
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    /// None when it stops
+    Playhead(Option<Playhead>),
+    Recorded(Recorded),
 }
 
-static TEMPO_INIT: u64 = 180;
+/// beats to a bar, everything is in 4/4
+pub const BEATS_PER_BAR: u32 = 4;
+
+/// clock ticks to a step, how finely hits can be placed off the grid
+pub const TICKS_PER_STEP: u64 = 96;
+
+/// the most swing there can be, any more and the swung step runs into the next one
+pub const MAX_SWING: u32 = 75;
+
+/// how many choke groups there are, they go from 1
+pub const CHOKE_GROUPS: u8 = 4;
+
+/// room in kira for every track's mixer tracks, and for a whole pattern's worth of mixer
+/// changes at once
+pub fn capacities() -> Capacities {
+    Capacities {
+        command_capacity: 1024,
+        sub_track_capacity: 512,
+        ..Default::default()
+    }
+}
+
+/// a track's strip volume and panning for kira, missing strips are at 0dB in the middle
+///
+/// heard: which tracks can be heard, from `mixer::audible`
+fn strip_settings(mix: &[ChannelStrip], heard: &[bool], track: usize) -> (Volume, f64) {
+    let strip = mix.get(track).copied().unwrap_or_default();
+    let volume = if heard.get(track).copied().unwrap_or(true) {
+        Volume::Decibels(strip.volume)
+    } else {
+        Volume::Amplitude(0.0)
+    };
+    (volume, strip.panning())
+}
 
-pub struct PlayBack {
-    audio_manager: AudioManager,
-    soft_hits: TrackHandle,
-    accented_hits: TrackHandle,
+/// xorshift64*, random enough for drums and the same every time for a seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // all zeroes would stay zero forever
+        Rng(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    /// 0 to 99
+    fn percent(&mut self) -> u8 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        ((self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) % 100) as u8
+    }
+}
+
+/// clock ticks a step gets moved by its nudge
+fn nudge_ticks(nudge: i8) -> i64 {
+    nudge as i64 * TICKS_PER_STEP as i64 / NUDGES_PER_STEP as i64
+}
+
+/// how recorded hits go into a pattern
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recorder {
+    /// percent of the way recorded hits get pulled onto their step
+    pub quantize: u8,
+    /// add to whats there, otherwise the first hit on a track each time round clears it
+    pub overdub: bool,
+    /// the slot, track and time round of the last track cleared
+    wiped: Option<(usize, usize, u64)>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Recorder {
+            quantize: 100,
+            overdub: true,
+            wiped: None,
+        }
+    }
+}
+
+impl Recorder {
+    /// start another take, replacing clears the tracks again
+    pub fn restart(&mut self) {
+        self.wiped = None;
+    }
+
+    /// put a hit played in while recording into the pattern it landed in, quantized.
+    /// overdubbing keeps the rest of a step thats already there, eg: its locks
+    ///
+    /// returns an error if the step isnt in the pattern anymore
+    pub fn write(
+        &mut self,
+        sequence: &mut SampleSequence,
+        hit: Recorded,
+    ) -> Result<(), &'static str> {
+        let mut step = sequence
+            .get_step(hit.track, hit.step)
+            .ok_or("track or step index out of bounds")?;
+        let pass = (hit.slot, hit.track, hit.pass);
+        if !self.overdub && self.wiped != Some(pass) {
+            sequence.clear_track(hit.track);
+            self.wiped = Some(pass);
+            step = Step::default();
+        }
+        step.velocity = hit.level.velocity();
+        step.nudge = quantize(hit.offset, self.quantize);
+        sequence.put_step(hit.track, hit.step, step)
+    }
+}
+
+/// the nudge a recorded hit keeps after quantizing, full strength puts it right on the step
+///
+/// offset: how many ticks after the step it came
+///
+/// strength: percent of the way to the step it gets pulled
+pub fn quantize(offset: i64, strength: u8) -> i8 {
+    let kept = offset as f64 * (100 - strength.min(100)) as f64 / 100.0;
+    let nudge = (kept * NUDGES_PER_STEP as f64 / TICKS_PER_STEP as f64).round() as i64;
+    nudge.clamp(-MAX_NUDGE as i64, MAX_NUDGE as i64) as i8
+}
+
+/// a hit's settings with the step's locks on top
+///
+/// gain: amplitude the hit plays at before the gain lock, eg: for a decaying ratchet
+///
+/// frames: length of the sample, for where the start lock lands
+fn with_locks(
+    settings: StaticSoundSettings,
+    locks: &Locks,
+    gain: f64,
+    frames: usize,
+) -> StaticSoundSettings {
+    let db = locks.gain.unwrap_or(0) as f64;
+    let mut settings = settings.volume(gain * Volume::Decibels(db).as_amplitude());
+    if let Some(pitch) = locks.pitch {
+        settings = settings.playback_rate(PlaybackRate::Semitones(pitch as f64));
+    }
+    if let Some(pan) = locks.pan {
+        // kira pans from 0 left to 1 right
+        settings = settings.panning((pan as f64 + 100.0) / 200.0);
+    }
+    if let Some(start) = locks.start {
+        let from = (frames * start as usize / 100) as i64;
+        settings = settings.playback_region(PlaybackPosition::Samples(from)..);
+    }
+    settings
+}
+
+static TEMPO_INIT: u32 = 180;
+
+/// how long a mixer track takes to get to a new gain or pan
+static MIX_FADE: Duration = Duration::from_millis(20);
+
+/// how long a choked sound takes to fade out
+static CHOKE_FADE: Duration = Duration::from_millis(10);
+
+/// how often the playback loop checks on the clock
+static POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// how far ahead of the clock hits get queued, has to cover the audio buffer
+static LOOKAHEAD: Duration = Duration::from_millis(50);
+
+/// A track's way through the mixer
+struct Channel {
+    /// volume of the whole track, it sends to the buses
+    strip: TrackHandle,
+    filter: FilterHandle,
+    distortion: DistortionHandle,
+    delay: DelayHandle,
+    panning: PanningControlHandle,
+    /// mixer tracks for soft, regular and loud hits, they feed the strip
+    accents: [TrackHandle; 3],
+}
+
+impl Channel {
+    /// the accent mixer track a level's hits go through, None for silent
+    fn accent(&self, level: AccentLevel) -> Option<&TrackHandle> {
+        match level {
+            AccentLevel::Silent => None,
+            AccentLevel::Soft => Some(&self.accents[0]),
+            AccentLevel::Regular => Some(&self.accents[1]),
+            AccentLevel::Loud => Some(&self.accents[2]),
+        }
+    }
+}
+
+/// The send buses every track shares
+struct Buses {
+    reverb: TrackHandle,
+    reverb_fx: ReverbHandle,
+    delay: TrackHandle,
+    delay_fx: DelayHandle,
+}
+
+/// A sound playing on a track in a choke group
+struct Voice {
+    group: u8,
+    /// the clock tick it starts on
+    tick: u64,
+    /// the tick its been cut off at, None if it hasnt
+    cut: Option<u64>,
+    handle: StaticSoundHandle,
+}
+
+pub struct PlayBack<B: Backend = CpalBackend> {
+    audio_manager: AudioManager<B>,
+    /// one per sample, in track order
+    channels: Vec<Channel>,
+    /// channels of tracks that went, kept till the next swap so they can fade out
+    retired: Vec<Channel>,
+    buses: Buses,
+    /// every track's effects
+    effects: Vec<Effects>,
+    /// the send buses' effects
+    bus_effects: Effects,
+    /// every track's choke group, None for tracks that dont choke
+    choke: Vec<Option<u8>>,
+    /// sounds that can still get choked
+    voices: Vec<Voice>,
+    /// global gain of each accent level
+    accents: AccentGains,
+    /// per track accent gains, None for the global ones
+    track_accents: Vec<Option<AccentGains>>,
+    /// every track's channel strip
+    mix: Vec<ChannelStrip>,
+    clock: ClockHandle,
+    /// clock ticks a minute, tempo * divisions * TICKS_PER_STEP
+    tick_rate: f64,
+    /// steps to a beat
+    divisions: u32,
     mute: bool,
-    sequence: SampleSequence,
+    swing: u32,
+    track_swing: Vec<Option<u32>>,
+    /// every pattern, by slot
+    bank: Vec<SampleSequence>,
+    /// the slot thats playing
+    playing: usize,
+    /// the slot to switch to when the playing one wraps around
+    cued: usize,
+    /// tracks of the playing pattern
+    sequence_tracks: Vec<Track>,
     samples: ActiveSamples,
+    /// how many steps have been queued since the start
+    position: u64,
+    /// the position the playing pattern started at, tracks wrap around from here on their own
+    start: u64,
+    song: Option<Song>,
+    /// the song entry thats playing, and how many times round its pattern has been
+    song_at: Option<(usize, u32)>,
+    /// the song stopped at its end, nothing plays till it changes
+    song_done: bool,
+    fill: bool,
+    curve: VelocityCurve,
+    rng: Rng,
+    /// did the last conditional step on each track play, for the Pre conditions
+    prev_played: Vec<bool>,
+    /// the clock tick the next step is on, before any swing
+    next_tick: u64,
+    /// steps dropped for being late
+    dropped: u64,
+    /// steps recorded ahead of the playhead, by track and position since the start. their
+    /// hit has been heard already, they dont play till the next time round
+    skip: Vec<(usize, u64)>,
 }
 
 impl PlayBack {
@@ -35,38 +409,834 @@ impl PlayBack {
     ///
     /// returns an error if the samples are empty, or if it cant spawn an "audiomanager"
     pub fn setup(samples: ActiveSamples) -> Result<PlayBack, Box<dyn Error>> {
-        let mut m = AudioManager::<CpalBackend>::new(AudioManagerSettings::default())?;
-        if samples.len() == 0 {
-            return Err(Box::<dyn Error>::from("Empty sample bank"));
-        }
-        // soft and loud mixer tracks for accent levels
-        let soft_hits = m.add_sub_track(TrackBuilder::new().volume(Volume::Decibels(-6.0)))?;
-        let accented_hits = m.add_sub_track(TrackBuilder::new().volume(Volume::Decibels(6.0)))?;
-        Ok(PlayBack {
+        let m = AudioManager::<CpalBackend>::new(AudioManagerSettings {
+            capacities: capacities(),
+            ..Default::default()
+        })?;
+        PlayBack::with_manager(m, samples)
+    }
+}
+
+impl PlayBack<MockBackend> {
+    /// process a block of frames on the mock backend, the same as a sound card would
+    pub fn process(&mut self, frames: usize) -> Vec<Frame> {
+        let backend = self.audio_manager.backend_mut();
+        backend.on_start_processing();
+        (0..frames).map(|_| backend.process()).collect()
+    }
+}
+
+impl<B: Backend> PlayBack<B> {
+    /// set up playback on an already running audio manager, eg: a mock one for rendering
+    ///
+    /// returns an error if the samples are empty, or if the mixer tracks cant be added
+    pub fn with_manager(
+        mut m: AudioManager<B>,
+        samples: ActiveSamples,
+    ) -> Result<PlayBack<B>, Box<dyn Error>> {
+        // the buses are all wet, the dry sound goes out the track
+        let fx = Effects::default();
+        let mut builder = TrackBuilder::new();
+        let reverb_fx = builder.add_effect(
+            ReverbBuilder::new()
+                .feedback(effects::fraction(fx.get(Knob::ReverbSize)))
+                .damping(effects::fraction(fx.get(Knob::ReverbDamping)))
+                .mix(1.0),
+        );
+        let reverb = m.add_sub_track(builder)?;
+        let mut builder = TrackBuilder::new();
+        let delay_fx = builder.add_effect(
+            DelayBuilder::new()
+                .buffer_length(MAX_DELAY_SECONDS + 0.1)
+                .feedback(Volume::Amplitude(effects::fraction(
+                    fx.get(Knob::BusFeedback),
+                )))
+                .mix(1.0),
+        );
+        let delay = m.add_sub_track(builder)?;
+
+        let clock = m.add_clock(ClockSpeed::TicksPerMinute(TEMPO_INIT as f64))?;
+        let mut playback = PlayBack {
             audio_manager: m,
-            soft_hits,
-            accented_hits,
+            channels: vec![],
+            retired: vec![],
+            buses: Buses {
+                reverb,
+                reverb_fx,
+                delay,
+                delay_fx,
+            },
+            effects: vec![],
+            bus_effects: fx,
+            choke: vec![],
+            voices: vec![],
+            clock,
+            tick_rate: TEMPO_INIT as f64,
+            divisions: 1,
             mute: true,
-            sequence: Default::default(),
-            samples,
+            swing: 0,
+            track_swing: vec![],
+            bank: vec![],
+            playing: 0,
+            cued: 0,
+            sequence_tracks: vec![],
+            samples: vec![],
+            position: 0,
+            start: 0,
+            song: None,
+            song_at: None,
+            song_done: false,
+            fill: false,
+            curve: VelocityCurve::default(),
+            accents: AccentGains::default(),
+            track_accents: vec![],
+            mix: vec![],
+            rng: Rng::new(0),
+            prev_played: vec![],
+            next_tick: 0,
+            dropped: 0,
+            skip: vec![],
+        };
+        playback.set_samples(samples, vec![])?;
+        Ok(playback)
+    }
+
+    /// swap in another bank of samples without stopping, eg: a different kit or the same one
+    /// with some samples changed. tracks that move take their mixer settings and effects with
+    /// them, new ones start where the settings have them. the ones that go fade out. takes
+    /// effect from the next unqueued step
+    ///
+    /// from: the track each one was before, None for new ones
+    ///
+    /// returns an error if the samples are empty, or if the mixer tracks cant be added
+    pub fn set_samples(
+        &mut self,
+        samples: ActiveSamples,
+        from: Vec<Option<usize>>,
+    ) -> Result<(), Box<dyn Error>> {
+        if samples.is_empty() {
+            return Err(Box::<dyn Error>::from("Empty sample bank"));
+        }
+        let was_heard = mixer::audible(&self.mix);
+        self.mix = samples::remap(&self.mix, &from, ChannelStrip::default());
+        self.effects = samples::remap(&self.effects, &from, Effects::default());
+        self.track_accents = samples::remap(&self.track_accents, &from, None);
+        self.track_swing = samples::remap(&self.track_swing, &from, None);
+        self.choke = samples::remap(&self.choke, &from, None);
+        self.prev_played = samples::remap(&self.prev_played, &from, false);
+
+        let tween = self.fade();
+        let heard = mixer::audible(&self.mix);
+        let mut old: Vec<Option<Channel>> = self.channels.drain(..).map(Some).collect();
+        for (track, was) in from.iter().enumerate().take(samples.len()) {
+            let moved = was.and_then(|t| Some((t, old.get_mut(t)?.take()?)));
+            let channel = match moved {
+                // a solo that went can change who else is heard
+                Some((was, channel)) => {
+                    if was_heard.get(was) != heard.get(track) {
+                        let (volume, _) = strip_settings(&self.mix, &heard, track);
+                        channel.strip.set_volume(volume, tween)?;
+                    }
+                    channel
+                }
+                None => self.new_channel(track)?,
+            };
+            self.channels.push(channel);
+        }
+        while self.channels.len() < samples.len() {
+            let channel = self.new_channel(self.channels.len())?;
+            self.channels.push(channel);
+        }
+        // dropping a channel's handles takes its mixer tracks out, the ones that go get to
+        // fade out first
+        self.retired = old.into_iter().flatten().collect();
+        for channel in self.retired.iter_mut() {
+            channel.strip.set_volume(Volume::Amplitude(0.0), tween)?;
+        }
+        self.samples = samples;
+        Ok(())
+    }
+
+    /// the mixer tracks for a track. they start out where the settings already have them so
+    /// nothing needs to be sent to them before they're there
+    ///
+    /// returns an error if the mixer tracks cant be added
+    fn new_channel(&mut self, track: usize) -> Result<Channel, Box<dyn Error>> {
+        let fx = self.track_effects(track);
+        let (volume, panning) = strip_settings(&self.mix, &mixer::audible(&self.mix), track);
+        // the filter and distortion are taken right out when they're turned all the way off
+        let on = |on: bool| if on { 1.0 } else { 0.0 };
+        let send = |knob| Volume::Amplitude(effects::fraction(fx.get(knob)));
+
+        let mut builder = TrackBuilder::new().volume(volume).routes(
+            TrackRoutes::new()
+                .with_route(self.buses.reverb.id(), send(Knob::ReverbSend))
+                .with_route(self.buses.delay.id(), send(Knob::DelaySend)),
+        );
+        let cutoff = fx.get(Knob::Cutoff);
+        let filter = builder.add_effect(
+            FilterBuilder::new()
+                .cutoff(effects::cutoff_hz(cutoff))
+                .resonance(effects::fraction(fx.get(Knob::Resonance)))
+                .mix(on(cutoff < Knob::Cutoff.default_value())),
+        );
+        let drive = fx.get(Knob::Drive);
+        let distortion = builder.add_effect(
+            DistortionBuilder::new()
+                .kind(DistortionKind::SoftClip)
+                .drive(Volume::Decibels(drive as f64))
+                .mix(on(drive > 0)),
+        );
+        let delay = builder.add_effect(
+            DelayBuilder::new()
+                .buffer_length(MAX_DELAY_SECONDS + 0.1)
+                .delay_time(self.delay_seconds(fx.get(Knob::Delay)))
+                .feedback(Volume::Amplitude(effects::fraction(fx.get(Knob::Feedback))))
+                .mix(effects::fraction(fx.get(Knob::Wet))),
+        );
+        let panning = builder.add_effect(PanningControlBuilder(panning.into()));
+        let m = &mut self.audio_manager;
+        let strip = m.add_sub_track(builder)?;
+
+        let gains = self.track_accents.get(track).copied().flatten();
+        let gains = gains.unwrap_or(self.accents);
+        let mut accent = |level| {
+            m.add_sub_track(
+                TrackBuilder::new()
+                    .volume(Volume::Decibels(gains.get(level)))
+                    .routes(TrackRoutes::parent(strip.id())),
+            )
+        };
+        let accents = [
+            accent(AccentLevel::Soft)?,
+            accent(AccentLevel::Regular)?,
+            accent(AccentLevel::Loud)?,
+        ];
+        Ok(Channel {
+            strip,
+            filter,
+            distortion,
+            delay,
+            panning,
+            accents,
+        })
+    }
+
+    /// change the speed of the step clock, takes effect on the very next sample. the delays
+    /// follow it
+    pub fn set_tempo(&mut self, bpm: u32, divisions: u32) -> Result<(), Box<dyn Error>> {
+        let tick_rate = bpm as f64 * divisions as f64 * TICKS_PER_STEP as f64;
+        self.divisions = divisions;
+        let now = Tween {
+            duration: Duration::ZERO,
+            ..Default::default()
+        };
+        self.clock
+            .set_speed(ClockSpeed::TicksPerMinute(tick_rate), now)?;
+        if tick_rate != self.tick_rate {
+            self.tick_rate = tick_rate;
+            for track in 0..self.channels.len() {
+                let steps = self.track_effects(track).get(Knob::Delay);
+                self.turn(track, Knob::Delay, steps, now)?;
+            }
+            let steps = self.bus_effects.get(Knob::BusDelay);
+            self.turn(0, Knob::BusDelay, steps, now)?;
+        }
+        Ok(())
+    }
+
+    /// start or pause the transport, paused keeps its place in the sequence
+    pub fn set_mute(&mut self, mute: bool) -> Result<(), Box<dyn Error>> {
+        self.mute = mute;
+        if mute {
+            self.clock.pause()?;
+        } else {
+            self.clock.start()?;
+        }
+        Ok(())
+    }
+
+    /// start the dice over, the same seed gives the same steps played every time
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// turn fill on or off, takes effect from the next unqueued step
+    pub fn set_fill(&mut self, fill: bool) {
+        self.fill = fill;
+    }
+
+    /// change how loud each velocity is, takes effect from the next unqueued step
+    pub fn set_curve(&mut self, curve: VelocityCurve) {
+        self.curve = curve;
+    }
+
+    /// how changes to the mixer get there, stopped theres nothing to click so it goes
+    /// straight there
+    fn fade(&self) -> Tween {
+        Tween {
+            duration: if self.mute { Duration::ZERO } else { MIX_FADE },
+            ..Default::default()
+        }
+    }
+
+    /// change the accent gains, the accent mixer tracks fade to them
+    ///
+    /// track_accents: per track gains overriding the global ones, None to use the global ones
+    ///
+    /// returns an error if kira cant change the mixer tracks
+    pub fn set_accents(
+        &mut self,
+        accents: AccentGains,
+        track_accents: Vec<Option<AccentGains>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let tween = self.fade();
+        for (track, channel) in self.channels.iter_mut().enumerate() {
+            let was = self.track_accents.get(track).copied().flatten();
+            let was = was.unwrap_or(self.accents);
+            let gains = track_accents.get(track).copied().flatten();
+            let gains = gains.unwrap_or(accents);
+            if gains == was {
+                continue;
+            }
+            let levels = [AccentLevel::Soft, AccentLevel::Regular, AccentLevel::Loud];
+            for (mixer_track, level) in channel.accents.iter_mut().zip(levels) {
+                mixer_track.set_volume(Volume::Decibels(gains.get(level)), tween)?;
+            }
+        }
+        self.accents = accents;
+        self.track_accents = track_accents;
+        Ok(())
+    }
+
+    /// change the channel strips, the mixer fades to them
+    ///
+    /// mix: a strip for every track, missing ones are left in the middle at 0dB
+    ///
+    /// returns an error if kira cant change the mixer tracks
+    pub fn set_mix(&mut self, mix: Vec<ChannelStrip>) -> Result<(), Box<dyn Error>> {
+        let tween = self.fade();
+        let (was_heard, heard) = (mixer::audible(&self.mix), mixer::audible(&mix));
+        for (track, channel) in self.channels.iter_mut().enumerate() {
+            let (was_volume, was_panning) = strip_settings(&self.mix, &was_heard, track);
+            let (volume, panning) = strip_settings(&mix, &heard, track);
+            if volume != was_volume {
+                channel.strip.set_volume(volume, tween)?;
+            }
+            if panning != was_panning {
+                channel.panning.set_panning(panning, tween)?;
+            }
+        }
+        self.mix = mix;
+        Ok(())
+    }
+
+    /// change the effects, the knobs that moved fade to where they're at
+    ///
+    /// effects: every track's effects, missing ones are left at the defaults
+    ///
+    /// bus_effects: the send buses' effects, the track knobs in it dont do anything
+    ///
+    /// returns an error if kira cant change the effects
+    pub fn set_effects(
+        &mut self,
+        effects: Vec<Effects>,
+        bus_effects: Effects,
+    ) -> Result<(), Box<dyn Error>> {
+        let tween = self.fade();
+        for track in 0..self.channels.len() {
+            let was = self.track_effects(track);
+            let fx = effects.get(track).copied().unwrap_or_default();
+            for knob in Knob::all().into_iter().filter(|k| !k.is_bus()) {
+                if fx.get(knob) != was.get(knob) {
+                    self.turn(track, knob, fx.get(knob), tween)?;
+                }
+            }
+        }
+        self.effects = effects;
+        for knob in Knob::all().into_iter().filter(|k| k.is_bus()) {
+            if bus_effects.get(knob) != self.bus_effects.get(knob) {
+                self.turn(0, knob, bus_effects.get(knob), tween)?;
+            }
+        }
+        self.bus_effects = bus_effects;
+        Ok(())
+    }
+
+    /// a track's effects, the defaults if it hasnt got any
+    fn track_effects(&self, track: usize) -> Effects {
+        self.effects.get(track).copied().unwrap_or_default()
+    }
+
+    /// seconds some steps take at the tempo, as long as a delay can go
+    fn delay_seconds(&self, steps: u8) -> f64 {
+        let step = 60.0 * TICKS_PER_STEP as f64 / self.tick_rate;
+        (step * steps as f64).min(MAX_DELAY_SECONDS)
+    }
+
+    /// turn a knob in kira
+    ///
+    /// track: whose effects, bus knobs dont have one
+    ///
+    /// returns an error if kira cant change the effect
+    fn turn(
+        &mut self,
+        track: usize,
+        knob: Knob,
+        value: u8,
+        tween: Tween,
+    ) -> Result<(), Box<dyn Error>> {
+        let seconds = self.delay_seconds(value);
+        let amount = effects::fraction(value);
+        let buses = &mut self.buses;
+        if knob.is_bus() {
+            match knob {
+                Knob::ReverbSize => buses.reverb_fx.set_feedback(amount, tween)?,
+                Knob::ReverbDamping => buses.reverb_fx.set_damping(amount, tween)?,
+                Knob::BusDelay => buses.delay_fx.set_delay_time(seconds, tween)?,
+                _ => buses
+                    .delay_fx
+                    .set_feedback(Volume::Amplitude(amount), tween)?,
+            }
+            return Ok(());
+        }
+        let channel = &mut self.channels[track];
+        // the filter and distortion are taken right out when they're turned all the way off
+        let on = |on: bool| if on { 1.0 } else { 0.0 };
+        match knob {
+            Knob::Cutoff => {
+                channel
+                    .filter
+                    .set_cutoff(effects::cutoff_hz(value), tween)?;
+                channel
+                    .filter
+                    .set_mix(on(value < knob.default_value()), tween)?;
+            }
+            Knob::Resonance => channel.filter.set_resonance(amount, tween)?,
+            Knob::Drive => {
+                channel
+                    .distortion
+                    .set_drive(Volume::Decibels(value as f64), tween)?;
+                channel.distortion.set_mix(on(value > 0), tween)?;
+            }
+            Knob::Delay => channel.delay.set_delay_time(seconds, tween)?,
+            Knob::Feedback => channel
+                .delay
+                .set_feedback(Volume::Amplitude(amount), tween)?,
+            Knob::Wet => channel.delay.set_mix(amount, tween)?,
+            Knob::ReverbSend => {
+                channel
+                    .strip
+                    .set_route(buses.reverb.id(), Volume::Amplitude(amount), tween)?
+            }
+            _ => channel
+                .strip
+                .set_route(buses.delay.id(), Volume::Amplitude(amount), tween)?,
+        }
+        Ok(())
+    }
+
+    /// put tracks in choke groups, takes effect from the next unqueued step
+    ///
+    /// choke: every track's group, None or missing for tracks that dont choke
+    pub fn set_choke(&mut self, choke: Vec<Option<u8>>) {
+        self.choke = choke;
+    }
+
+    /// cut off the sounds in a choke group that start before a new one, or the new one if
+    /// theres one queued to start after it
+    ///
+    /// returns an error if kira cant stop the sounds
+    fn choke(
+        &mut self,
+        group: u8,
+        tick: u64,
+        mut handle: StaticSoundHandle,
+    ) -> Result<(), Box<dyn Error>> {
+        self.voices
+            .retain(|v| v.handle.state() != PlaybackState::Stopped);
+        let clock = self.clock.id();
+        let fade_at = |ticks| Tween {
+            start_time: StartTime::ClockTime(ClockTime { clock, ticks }),
+            duration: CHOKE_FADE,
+            ..Default::default()
+        };
+        let mut cut = None;
+        for voice in self.voices.iter_mut().filter(|v| v.group == group) {
+            // a sound already cut off sooner stays cut off there
+            if voice.tick < tick && voice.cut.map_or(true, |c| tick < c) {
+                voice.handle.stop(fade_at(tick))?;
+                voice.cut = Some(tick);
+            } else if voice.tick > tick {
+                cut = Some(cut.map_or(voice.tick, |c: u64| c.min(voice.tick)));
+            }
+        }
+        if let Some(at) = cut {
+            handle.stop(fade_at(at))?;
+        }
+        self.voices.push(Voice {
+            group,
+            tick,
+            cut,
+            handle,
+        });
+        Ok(())
+    }
+
+    /// change the swing, takes effect from the next unqueued step
+    ///
+    /// swing: percent of a step every second step is late, capped at MAX_SWING
+    ///
+    /// track_swing: per track swing overriding the global one, None to use the global one
+    pub fn set_swing(&mut self, swing: u32, track_swing: Vec<Option<u32>>) {
+        self.swing = swing.min(MAX_SWING);
+        self.track_swing = track_swing;
+    }
+
+    /// how many ticks late a track plays a step, every second step gets swung
+    ///
+    /// step: steps since the playing pattern started
+    fn swing_ticks(&self, track: usize, step: u64) -> u64 {
+        if step % 2 == 0 {
+            return 0;
+        }
+        let swing = match self.track_swing.get(track) {
+            Some(Some(s)) => (*s).min(MAX_SWING),
+            _ => self.swing,
+        };
+        swing as u64 * TICKS_PER_STEP / 100
+    }
+
+    /// put a pattern in a slot of the bank, if its the one playing it starts playing
+    /// from the next unqueued step
+    pub fn set_sequence(&mut self, slot: usize, seq: SampleSequence) {
+        if slot >= self.bank.len() {
+            self.bank.resize_with(slot + 1, Default::default);
+        }
+        if slot == self.playing {
+            if self.bank[slot].num_tracks() != seq.num_tracks() {
+                self.start = self.position;
+            }
+            self.sequence_tracks = seq.tracks();
+        }
+        self.bank[slot] = seq;
+    }
+
+    /// play another slot once the playing one wraps around, cueing the playing one
+    /// cancels a switch that hasnt happened yet
+    pub fn cue(&mut self, slot: usize) {
+        self.cued = slot;
+    }
+
+    /// play a song instead of just the cued pattern, or go back to pattern mode with None
+    ///
+    /// a new song starts from its first entry when the playing pattern wraps around, an
+    /// edited one carries on from where it was
+    pub fn set_song(&mut self, song: Option<Song>) {
+        if song == self.song {
+            return;
+        }
+        match (&self.song, &song) {
+            (Some(_), Some(new)) => {
+                if let Some((entry, _)) = self.song_at {
+                    if entry >= new.entries.len() {
+                        self.song_at = None;
+                    }
+                }
+            }
+            _ => self.song_at = None,
+        }
+        self.song = song;
+        self.song_done = false;
+    }
+
+    /// switch to the cued pattern if the playing one is back at its first step
+    fn switch_on_wrap(&mut self) {
+        let steps = self.bank[self.playing].steps() as u64;
+        if (self.position - self.start) % steps != 0 {
+            return;
+        }
+        self.advance_song();
+        let next = self.bank.get(self.cued).map_or(0, |s| s.steps());
+        if self.cued != self.playing && next > 0 {
+            self.playing = self.cued;
+            self.start = self.position;
+            self.sequence_tracks = self.bank[self.playing].tracks();
+        }
+    }
+
+    /// count one more time round the playing pattern and cue whatever the song plays next
+    fn advance_song(&mut self) {
+        let Some(song) = &self.song else {
+            return;
+        };
+        if self.song_done {
+            return;
+        }
+        let at = match self.song_at {
+            None => Some((0, 0)),
+            Some((entry, played)) if played + 1 < song.entries[entry].repeats => {
+                Some((entry, played + 1))
+            }
+            Some((entry, _)) => song.next(entry).map(|e| (e, 0)),
+        };
+        match at {
+            Some((entry, _)) if entry < song.entries.len() => {
+                self.cued = song.entries[entry].pattern;
+                self.song_at = at;
+            }
+            _ => self.song_done = true,
+        }
+    }
+
+    /// queue every step that starts within the lookahead of the clocks current time
+    ///
+    /// returns an error if kira cant play
+    pub fn schedule_ahead(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.bank.get(self.playing).map_or(0, |s| s.steps()) == 0 {
+            return Ok(());
+        }
+        let now = self.clock.time().ticks;
+        while self.next_tick < now {
+            // fell behind the clock, drop the late steps instead of playing them all at once
+            self.switch_on_wrap();
+            self.position += 1;
+            self.next_tick += TICKS_PER_STEP;
+            self.dropped += 1;
+        }
+        // steps get queued early enough for their hits to be nudged ahead of them
+        let ahead = (LOOKAHEAD.as_secs_f64() * self.tick_rate / 60.0).ceil() as u64
+            + 1
+            + nudge_ticks(MAX_NUDGE) as u64;
+        while self.next_tick <= now + ahead {
+            self.switch_on_wrap();
+            if !self.song_done {
+                self.play_step(self.position - self.start, self.next_tick)?;
+            }
+            self.position += 1;
+            self.next_tick += TICKS_PER_STEP;
+        }
+        Ok(())
+    }
+
+    /// queue every hit on a step to start at a clock tick
+    ///
+    /// position: steps since the playing pattern started, each track picks its own step from it
+    ///
+    /// tick: the clock tick the step is on, swung tracks play a little after it
+    ///
+    /// returns an error if kira cant play
+    fn play_step(&mut self, position: u64, tick: u64) -> Result<(), Box<dyn Error>> {
+        let tracks = self.sequence_tracks.len().min(self.samples.len());
+        if self.prev_played.len() < tracks {
+            self.prev_played.resize(tracks, false);
+        }
+        let at = self.start + position;
+        self.skip.retain(|&(_, skip)| skip >= at);
+        for track in 0..tracks {
+            if self.skip.contains(&(track, at)) {
+                continue;
+            }
+            let hits = &self.sequence_tracks[track];
+            let len = hits.len() as u64;
+            let step = hits[(position % len) as usize];
+            if !self.should_play(track, step, position / len) {
+                continue;
+            }
+            let Some(mixer_track) = self.channels[track].accent(step.level()) else {
+                continue;
+            };
+            let mixer_track = mixer_track.id();
+
+            let swing = self.swing_ticks(track, position);
+            // nudged early on the very first step has nowhere to go but the start
+            let start = (tick + swing).saturating_add_signed(nudge_ticks(step.nudge));
+            let velocity_gain = Volume::Decibels(self.curve.gain_db(step.velocity)).as_amplitude();
+            let sample_gain = Volume::Decibels(self.samples[track].gain).as_amplitude();
+            // a ratchet spreads its hits over whats left of the step after the swing
+            let gains = step.ratchet_gains();
+            let spacing = (TICKS_PER_STEP - swing) / gains.len() as u64;
+            for (i, gain) in gains.into_iter().enumerate() {
+                let time = ClockTime {
+                    clock: self.clock.id(),
+                    ticks: start + spacing * i as u64,
+                };
+                let sample = &self.samples[track].data;
+                let settings = StaticSoundSettings::new()
+                    .output_destination(mixer_track)
+                    .start_time(time);
+                let handle = self.audio_manager.play(sample.with_settings(with_locks(
+                    settings,
+                    &step.locks,
+                    gain * velocity_gain * sample_gain,
+                    sample.frames.len(),
+                )))?;
+                if let Some(Some(group)) = self.choke.get(track) {
+                    self.choke(*group, time.ticks, handle)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// play a track's sample straight away, eg: to hear what it sounds like. it goes through
+    /// the track's channel like a step with the level's preset velocity, whether the
+    /// transport is going or not. while its going it chokes and gets choked like a step
+    ///
+    /// returns an error if kira cant play
+    pub fn preview(&mut self, track: usize, level: AccentLevel) -> Result<(), Box<dyn Error>> {
+        let Some(mixer_track) = self.channels.get(track).and_then(|c| c.accent(level)) else {
+            return Ok(());
+        };
+        let sample = &self.samples[track];
+        let gain = self.curve.gain_db(level.velocity()) + sample.gain;
+        let settings = StaticSoundSettings::new()
+            .output_destination(mixer_track.id())
+            .volume(Volume::Decibels(gain).as_amplitude());
+        let handle = self
+            .audio_manager
+            .play(sample.data.with_settings(settings))?;
+        if let (false, Some(Some(group))) = (self.mute, self.choke.get(track)) {
+            self.choke(*group, self.clock.time().ticks, handle)?;
+        }
+        Ok(())
+    }
+
+    /// play a track's sample straight away like `preview`, and work out where in the
+    /// playing pattern it landed
+    ///
+    /// returns None for where it landed if nothing is playing, or an error if kira cant play
+    pub fn record(
+        &mut self,
+        track: usize,
+        level: AccentLevel,
+    ) -> Result<Option<Recorded>, Box<dyn Error>> {
+        self.preview(track, level)?;
+        if level == AccentLevel::Silent {
+            return Ok(None);
+        }
+        let Some((position, offset)) = self.playhead(track) else {
+            return Ok(None);
+        };
+        // a step thats still to be queued would play the hit again
+        if position >= self.position - self.start {
+            self.skip.push((track, self.start + position));
+        }
+        let len = self.sequence_tracks[track].len() as u64;
+        Ok(Some(Recorded {
+            slot: self.playing,
+            track,
+            step: (position % len) as usize,
+            pass: position / len,
+            offset,
+            level,
+        }))
+    }
+
+    /// where a track's playhead is on the clock: the step nearest to it in steps since the
+    /// playing pattern started, and how many ticks after the step it is
+    ///
+    /// None if the transport is stopped or its from before the playing pattern started
+    fn playhead(&self, track: usize) -> Option<(u64, i64)> {
+        let len = self.sequence_tracks.get(track).map_or(0, |t| t.len()) as i64;
+        if self.mute || self.song_done || len == 0 {
+            return None;
+        }
+        let step = TICKS_PER_STEP as i64;
+        let at = self.clock_ticks();
+        let below = at.div_euclid(step);
+        let (position, offset) = [below, below + 1]
+            .into_iter()
+            .filter(|&p| p >= 0)
+            .map(|p| (p, at - p * step - self.swing_ticks(track, p as u64) as i64))
+            .min_by_key(|(_, offset)| offset.abs())?;
+        Some((position as u64, offset))
+    }
+
+    /// where the clock is, in ticks since the playing pattern started. the clock is somewhere
+    /// behind the steps that have been queued, so its negative while the last pattern is
+    /// still playing out
+    fn clock_ticks(&self) -> i64 {
+        let now = self.clock.time().ticks as i64;
+        (self.position - self.start) as i64 * TICKS_PER_STEP as i64 - (self.next_tick as i64 - now)
+    }
+
+    /// the step the clock is on in the playing pattern, None if the transport is stopped or
+    /// nothing is playing
+    pub fn now_playing(&self) -> Option<Playhead> {
+        let steps = self.bank.get(self.playing).map_or(0, |s| s.steps()) as u64;
+        let at = self.clock_ticks();
+        if self.mute || steps == 0 || self.song_done || at < 0 {
+            return None;
+        }
+        let position = at as u64 / TICKS_PER_STEP;
+        let step = (position % steps) as usize;
+        Some(Playhead {
+            slot: self.playing,
+            position,
+            step,
+            bar: step / (self.divisions * BEATS_PER_BAR).max(1) as usize,
+            loops: position / steps,
+            dropped: self.dropped,
         })
     }
+
+    /// roll the dice and check the condition for a step
+    ///
+    /// loops: how many times round its track has been
+    fn should_play(&mut self, track: usize, step: Step, loops: u64) -> bool {
+        if step.level() == AccentLevel::Silent {
+            return false;
+        }
+        if !step.is_conditional() {
+            return true;
+        }
+        let prev = self.prev_played[track];
+        let play = match step.condition {
+            Condition::Always => true,
+            Condition::First => loops == 0,
+            Condition::NotFirst => loops > 0,
+            Condition::Every { nth, of } => loops % of.max(1) as u64 + 1 == nth as u64,
+            Condition::Fill => self.fill,
+            Condition::NotFill => !self.fill,
+            Condition::Pre => prev,
+            Condition::NotPre => !prev,
+        } && (step.probability >= 100 || self.rng.percent() < step.probability);
+
+        // the pre conditions look back past each other
+        if !matches!(step.condition, Condition::Pre | Condition::NotPre) {
+            self.prev_played[track] = play;
+        }
+        play
+    }
 }
 
 pub trait Player {
     /// the playback loop
     ///
-    /// sequence_rx: channel to receive sequence changes
+    /// sequence_rx: channel to receive pattern changes, the slot in the bank and the pattern
     ///
     /// control_rx: channel to receive control commands
     ///
+    /// sample_rx: channel to receive another bank of samples, eg: when the kit changes, and
+    /// the track each one was before
+    ///
+    /// preview_rx: channel to receive tracks to play straight away, the level to play them
+    /// at and whether theyre being recorded
+    ///
+    /// status_tx: channel to send back where playback is when it moves on a step, and where
+    /// recorded hits landed
+    ///
     /// division: how many steps to a beat, the usual music sense
     ///
     /// returns an error if kira cant play
     fn begin_playback(
         &mut self,
-        sequence_rx: Receiver<SampleSequence>,
+        sequence_rx: Receiver<(usize, SampleSequence)>,
         control_rx: Receiver<Controls>,
+        sample_rx: Receiver<(ActiveSamples, Vec<Option<usize>>)>,
+        preview_rx: Receiver<(usize, AccentLevel, bool)>,
+        status_tx: Sender<Status>,
         divisions: u32,
     ) -> Result<(), Box<dyn Error>>;
 }
@@ -74,65 +1244,407 @@ pub trait Player {
 impl Player for PlayBack {
     fn begin_playback(
         &mut self,
-        sequence_rx: Receiver<SampleSequence>,
+        sequence_rx: Receiver<(usize, SampleSequence)>,
         control_rx: Receiver<Controls>,
+        sample_rx: Receiver<(ActiveSamples, Vec<Option<usize>>)>,
+        preview_rx: Receiver<(usize, AccentLevel, bool)>,
+        status_tx: Sender<Status>,
         division: u32,
     ) -> Result<(), Box<dyn Error>> {
-        self.mute = false;
-        let num_tracks = if self.sequence.num_tracks() > self.samples.len() {
-            self.sequence.num_tracks()
-        } else {
-            self.samples.len()
-        };
-
-        let mut sequence_tracks = self.sequence.tracks();
-        let mut step = 0;
-        let mut bpm: u64 = TEMPO_INIT;
+        self.set_tempo(TEMPO_INIT, division)?;
+        self.set_mute(false)?;
+        // live is never the same twice
+        self.set_seed(SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64);
+        // the last playhead sent back
+        let mut heard = None;
 
         loop {
-            spin_sleep::sleep(Duration::from_millis(60_000 / (bpm * division as u64)));
-            match control_rx.try_recv() {
-                Ok(ctrl) => {
-                    bpm = ctrl.tempo as u64;
-                    self.mute = ctrl.mute;
+            thread::sleep(POLL_INTERVAL);
+            // the tracks' new settings come after their samples, their mixer tracks need to
+            // be there for them
+            if let Ok((samples, from)) = sample_rx.try_recv() {
+                self.set_samples(samples, from)?;
+            }
+            while let Ok((track, level, record)) = preview_rx.try_recv() {
+                if !record {
+                    self.preview(track, level)?;
+                } else if let Some(hit) = self.record(track, level)? {
+                    // the control loop going away means its all stopping anyway
+                    let _ = status_tx.send(Status::Recorded(hit));
                 }
-                Err(_) => (),
             }
-
-            if self.mute {
-                continue;
+            if let Ok(ctrl) = control_rx.try_recv() {
+                self.set_tempo(ctrl.tempo, ctrl.divisions)?;
+                if ctrl.mute != self.mute {
+                    self.set_mute(ctrl.mute)?;
+                }
+                self.set_swing(ctrl.swing, ctrl.track_swing);
+                self.cue(ctrl.pattern);
+                self.set_song(ctrl.song);
+                self.set_fill(ctrl.fill);
+                self.set_curve(ctrl.curve);
+                self.set_accents(ctrl.accents, ctrl.track_accents)?;
+                self.set_mix(ctrl.mix)?;
+                self.set_effects(ctrl.effects, ctrl.bus_effects)?;
+                self.set_choke(ctrl.choke);
             }
 
-            match sequence_rx.try_recv() {
-                Ok(seq) => {
-                    if self.sequence.num_tracks() != seq.num_tracks() {
-                        step = 0;
-                    }
-                    self.sequence = seq;
-                    sequence_tracks = self.sequence.tracks();
-                }
-                Err(_) => (),
-            };
+            // the whole bank comes in one go at the start, dont take a poll per pattern
+            while let Ok((slot, seq)) = sequence_rx.try_recv() {
+                self.set_sequence(slot, seq);
+            }
 
             /* === The actual playback logic === */
 
-            for track in 0..num_tracks {
-                let mixer_track = match sequence_tracks[track][step] {
-                    AccentLevel::Silent => self.soft_hits.id(),
-                    AccentLevel::Loud => self.accented_hits.id(),
-                    _ => self.audio_manager.main_track().id(),
-                };
+            self.schedule_ahead()?;
 
-                if sequence_tracks[track][step] != AccentLevel::Silent {
-                    self.audio_manager.play(
-                        self.samples[track]
-                            .clone()
-                            .with_settings(StaticSoundSettings::new().track(mixer_track)),
-                    )?;
-                }
+            let at = self.now_playing();
+            if at != heard {
+                heard = at;
+                let _ = status_tx.send(Status::Playhead(at));
             }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{capacities, quantize, PlayBack, Playhead, Recorded, Recorder};
+    use crate::mixer::ChannelStrip;
+    use crate::samples::Sample;
+    use crate::sequencer::{AccentLevel, Condition, SampleSequence, Sequence, Step};
+    use kira::dsp::Frame;
+    use kira::manager::backend::mock::{MockBackend, MockBackendSettings};
+    use kira::manager::{AudioManager, AudioManagerSettings};
+    use kira::sound::static_sound::{StaticSoundData, StaticSoundSettings};
+    use std::sync::Arc;
+
+    fn player() -> PlayBack<MockBackend> {
+        let m = AudioManager::<MockBackend>::new(AudioManagerSettings::default()).unwrap();
+        let silence = StaticSoundData {
+            sample_rate: 1000,
+            frames: Arc::from(vec![]),
+            settings: StaticSoundSettings::default(),
+        };
+        PlayBack::with_manager(m, vec![Sample::new("silence", silence)]).unwrap()
+    }
+
+    #[test]
+    fn conditions() {
+        let mut p = player();
+        p.prev_played = vec![false];
+        let step = |condition| Step {
+            condition,
+            ..AccentLevel::Regular.into()
+        };
+
+        let every = step(Condition::Every { nth: 2, of: 3 });
+        let played: Vec<bool> = (0..6).map(|l| p.should_play(0, every, l)).collect();
+        assert_eq!(played, vec![false, true, false, false, true, false]);
+
+        assert!(p.should_play(0, step(Condition::First), 0));
+        assert!(!p.should_play(0, step(Condition::First), 1));
+        assert!(p.should_play(0, step(Condition::NotFirst), 1));
+
+        assert!(p.should_play(0, step(Condition::NotFill), 0));
+        p.set_fill(true);
+        assert!(p.should_play(0, step(Condition::Fill), 0));
+        assert!(!p.should_play(0, step(Condition::NotFill), 0));
+
+        // pre looks at the last conditional step, not at other pres
+        p.should_play(0, step(Condition::Fill), 0);
+        assert!(p.should_play(0, step(Condition::Pre), 0));
+        assert!(!p.should_play(0, step(Condition::NotPre), 0));
+        assert!(p.should_play(0, step(Condition::Pre), 0));
+        p.should_play(0, step(Condition::First), 1);
+        assert!(!p.should_play(0, step(Condition::Pre), 0));
+
+        assert!(!p.should_play(0, Step::default(), 0));
+    }
 
-            step = (step + 1) % self.sequence.steps();
+    #[test]
+    fn probability_is_seeded() {
+        let maybe = |probability| Step {
+            velocity: AccentLevel::Regular.velocity(),
+            probability,
+            condition: Condition::Always,
+            ..Default::default()
+        };
+        let roll = |seed, probability| {
+            let mut p = player();
+            p.prev_played = vec![false];
+            p.set_seed(seed);
+            (0..10000)
+                .map(|_| p.should_play(0, maybe(probability), 0))
+                .collect::<Vec<bool>>()
+        };
+        let first = roll(7, 30);
+        assert_eq!(first, roll(7, 30));
+        assert_ne!(first, roll(8, 30));
+        // every percent is as likely as the rest, so the chance comes out as asked for
+        for probability in [10, 30, 50, 90] {
+            let hits = roll(7, probability).iter().filter(|&&h| h).count() as i64;
+            let want = probability as i64 * 100;
+            assert!((hits - want).abs() < 200, "{} out of 10000", hits);
         }
     }
+
+    #[test]
+    fn swapping_samples() {
+        let mut p = player();
+        let sample = p.samples[0].clone();
+        p.set_samples(vec![sample.clone(); 3], vec![Some(0)])
+            .unwrap();
+        assert_eq!(p.channels.len(), 3);
+        let ids: Vec<_> = p.channels.iter().map(|c| c.strip.id()).collect();
+
+        // channels go with their tracks, the ones left out fade and go
+        let strip = ChannelStrip {
+            volume: -6.0,
+            ..Default::default()
+        };
+        p.set_mix(vec![
+            ChannelStrip::default(),
+            strip,
+            ChannelStrip::default(),
+        ])
+        .unwrap();
+        p.set_samples(vec![sample.clone(); 2], vec![Some(1), None])
+            .unwrap();
+        assert_eq!(p.channels.len(), 2);
+        assert_eq!(p.samples.len(), 2);
+        assert_eq!(p.channels[0].strip.id(), ids[1]);
+        assert!(!ids.contains(&p.channels[1].strip.id()));
+        assert_eq!(p.retired.len(), 2);
+        assert_eq!(p.mix, vec![strip, ChannelStrip::default()]);
+
+        assert!(p.set_samples(vec![], vec![]).is_err());
+        assert_eq!(p.channels.len(), 2);
+
+        // the playing pattern can have more tracks than there are samples for a bit
+        let mut seq = SampleSequence::new(3, 1);
+        seq.set_step(2, 0, AccentLevel::Loud).unwrap();
+        p.set_sequence(0, seq);
+        p.play_step(0, 0).unwrap();
+    }
+
+    #[test]
+    fn cued_patterns_wait_for_the_wrap() {
+        let mut p = player();
+        p.set_sequence(0, SampleSequence::new(1, 4));
+        p.set_sequence(1, SampleSequence::new(1, 3));
+
+        p.cue(1);
+        p.position = 2;
+        p.switch_on_wrap();
+        assert_eq!(p.playing, 0);
+        p.position = 4;
+        p.switch_on_wrap();
+        assert_eq!(p.playing, 1);
+        assert_eq!(p.start, 4);
+
+        // slot 1 is 3 steps long, so it wraps at 7 not 8
+        p.cue(0);
+        p.position = 6;
+        p.switch_on_wrap();
+        assert_eq!(p.playing, 1);
+        p.position = 7;
+        p.switch_on_wrap();
+        assert_eq!(p.playing, 0);
+
+        // edits to the playing pattern go straight in, others just wait in the bank
+        let mut edit = SampleSequence::new(1, 4);
+        edit.set_step(0, 1, AccentLevel::Loud).unwrap();
+        p.set_sequence(0, edit.get_sequence());
+        assert_eq!(p.sequence_tracks, edit.tracks());
+        p.set_sequence(5, SampleSequence::new(1, 2));
+        assert_eq!(p.sequence_tracks, edit.tracks());
+        assert_eq!(p.bank.len(), 6);
+
+        // empty slots dont get switched to
+        p.cue(3);
+        p.switch_on_wrap();
+        assert_eq!(p.playing, 0);
+    }
+
+    #[test]
+    fn previews_play_while_stopped() {
+        let rate = 1000;
+        let m = AudioManager::<MockBackend>::new(AudioManagerSettings {
+            backend_settings: MockBackendSettings { sample_rate: rate },
+            capacities: capacities(),
+            ..Default::default()
+        })
+        .unwrap();
+        let click = StaticSoundData {
+            sample_rate: rate,
+            frames: Arc::from(vec![Frame::from_mono(0.5), Frame::ZERO]),
+            settings: StaticSoundSettings::default(),
+        };
+        let mut p = PlayBack::with_manager(m, vec![Sample::new("click", click); 2]).unwrap();
+        p.process(1);
+
+        p.preview(1, AccentLevel::Loud).unwrap();
+        p.preview(0, AccentLevel::Silent).unwrap();
+        let hits: Vec<(usize, f32)> = p
+            .process(10)
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.left.abs() > 1e-4)
+            .map(|(i, f)| (i, f.left))
+            .collect();
+        assert_eq!(hits.len(), 1, "{:?}", hits);
+        assert!(hits[0].0 <= 1, "{:?}", hits);
+        // a loud hit is 6dB up
+        assert!((hits[0].1 - 0.998).abs() < 0.01, "{:?}", hits);
+    }
+
+    #[test]
+    fn recorded_hits_find_the_nearest_step() {
+        let mut p = player();
+        // recording plays the hit, kira wont play a sample with nothing in it
+        let blip = StaticSoundData {
+            sample_rate: 1000,
+            frames: Arc::from(vec![Frame::ZERO]),
+            settings: StaticSoundSettings::default(),
+        };
+        p.set_samples(vec![Sample::new("blip", blip)], vec![Some(0)])
+            .unwrap();
+        p.set_sequence(0, SampleSequence::new(1, 3));
+        // the clock stays on tick 0, the queued steps run on ahead of it
+        p.mute = false;
+        p.position = 4;
+        p.next_tick = 230;
+        let hit = |p: &mut PlayBack<MockBackend>| p.record(0, AccentLevel::Loud).unwrap();
+        assert_eq!(
+            hit(&mut p),
+            Some(Recorded {
+                slot: 0,
+                track: 0,
+                step: 2,
+                pass: 0,
+                offset: -38,
+                level: AccentLevel::Loud,
+            })
+        );
+
+        // late for a swung step is still on it
+        p.set_swing(50, vec![None]);
+        assert_eq!(hit(&mut p).map(|h| (h.step, h.offset)), Some((1, 10)));
+
+        p.next_tick = 100;
+        assert_eq!(hit(&mut p).map(|h| (h.step, h.pass)), Some((0, 1)));
+        assert!(p.skip.is_empty());
+
+        // early for a step thats still to be queued, it sits out the time round its heard on
+        p.next_tick = 20;
+        assert_eq!(hit(&mut p).map(|h| (h.step, h.pass)), Some((1, 1)));
+        assert_eq!(p.skip, vec![(0, 4)]);
+        p.play_step(4, 0).unwrap();
+        assert_eq!(p.skip, vec![(0, 4)]);
+        p.play_step(5, 0).unwrap();
+        assert!(p.skip.is_empty());
+
+        p.mute = true;
+        assert_eq!(hit(&mut p), None);
+    }
+
+    #[test]
+    fn quantizing() {
+        assert_eq!(quantize(-38, 100), 0);
+        assert_eq!(quantize(-38, 0), -10);
+        assert_eq!(quantize(-38, 50), -5);
+        assert_eq!(quantize(20, 75), 1);
+        assert_eq!(quantize(500, 0), 12);
+    }
+
+    #[test]
+    fn recording_over_a_track() {
+        let mut seq = SampleSequence::new(2, 4);
+        let locked = Step {
+            ratchet: 2,
+            ..AccentLevel::Soft.into()
+        };
+        seq.put_step(0, 1, locked).unwrap();
+        seq.set_step(0, 3, AccentLevel::Regular).unwrap();
+        seq.set_step(1, 0, AccentLevel::Regular).unwrap();
+        let hit = |step, pass| Recorded {
+            slot: 0,
+            track: 0,
+            step,
+            pass,
+            offset: 20,
+            level: AccentLevel::Loud,
+        };
+
+        // overdubbing keeps the rest of the step and the rest of the track
+        let mut recorder = Recorder {
+            quantize: 0,
+            ..Default::default()
+        };
+        recorder.write(&mut seq, hit(1, 0)).unwrap();
+        let step = seq.get_step(0, 1).unwrap();
+        assert_eq!((step.velocity, step.nudge, step.ratchet), (127, 5, 2));
+        assert!(seq.get_step(0, 3).unwrap().velocity > 0);
+
+        // replacing clears the track on the first hit each time round, only that track
+        recorder.overdub = false;
+        recorder.quantize = 100;
+        recorder.write(&mut seq, hit(2, 0)).unwrap();
+        recorder.write(&mut seq, hit(0, 0)).unwrap();
+        let played = |seq: &SampleSequence| -> Vec<u8> {
+            (0..4)
+                .map(|i| seq.get_step(0, i).unwrap().velocity)
+                .collect()
+        };
+        assert_eq!(played(&seq), vec![127, 0, 127, 0]);
+        assert_eq!(seq.get_step(1, 0).unwrap().velocity, 96);
+        assert_eq!(seq.get_step(0, 2).unwrap().ratchet, 1);
+
+        recorder.write(&mut seq, hit(3, 1)).unwrap();
+        assert_eq!(played(&seq), vec![0, 0, 0, 127]);
+        recorder.restart();
+        recorder.write(&mut seq, hit(1, 1)).unwrap();
+        assert_eq!(played(&seq), vec![0, 127, 0, 0]);
+
+        assert!(recorder.write(&mut seq, hit(4, 1)).is_err());
+    }
+
+    #[test]
+    fn playhead() {
+        let mut p = player();
+        assert_eq!(p.now_playing(), None);
+        p.set_sequence(0, SampleSequence::new(1, 12));
+        p.set_tempo(120, 2).unwrap();
+        p.mute = false;
+        // the clock stays on tick 0, the queued steps run on ahead of it
+        p.position = 30;
+        p.start = 2;
+        p.next_tick = 2000;
+        p.dropped = 3;
+        assert_eq!(
+            p.now_playing(),
+            Some(Playhead {
+                slot: 0,
+                position: 7,
+                step: 7,
+                bar: 0,
+                loops: 0,
+                dropped: 3,
+            })
+        );
+        p.next_tick = 200;
+        let at = p.now_playing().unwrap();
+        assert_eq!((at.position, at.step, at.bar, at.loops), (25, 1, 0, 2));
+        p.next_tick = 1888;
+        assert_eq!(p.now_playing().map(|at| at.bar), Some(1));
+
+        p.mute = true;
+        assert_eq!(p.now_playing(), None);
+        p.mute = false;
+
+        // the last pattern is still playing out
+        p.start = 29;
+        assert_eq!(p.now_playing(), None);
+    }
 }
@@ -0,0 +1,617 @@
+/**
+Offline rendering, bounces a pattern to a wav file faster than real time.
+
+Goes through the same clock and mixer tracks as live playback, just on kira's mock
+backend so we get to pull the frames out ourselves. Either loops the pattern that plays
+first, or goes through the song once from start to end. The first step cant be nudged
+before the start, so it plays on the first frame.
+*/
+use kira::dsp::Frame;
+use kira::manager::backend::mock::{MockBackend, MockBackendSettings};
+use kira::manager::{AudioManager, AudioManagerSettings};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::pattern::Pattern;
+use crate::playback::{capacities, PlayBack};
+use crate::samples::ActiveSamples;
+use crate::sequencer::Sequence;
+use crate::song::Song;
+
+pub static SAMPLE_RATE: u32 = 48_000;
+
+/// how many blocks a second get processed between scheduling, like a sound card would
+static BLOCKS_PER_SECOND: u32 = 1000;
+
+/// Render the pattern that plays first to stereo frames
+///
+/// samples: one sample per track, in track order
+///
+/// loops: how many times to go through the pattern
+///
+/// sample_rate: frames per second of the output
+///
+/// returns an error if the samples are empty, or kira cant play
+pub fn render(
+    pattern: &Pattern,
+    samples: ActiveSamples,
+    loops: u32,
+    sample_rate: u32,
+) -> Result<Vec<Frame>, Box<dyn Error>> {
+    let steps = pattern.sequence().steps() * loops as usize;
+    render_steps(pattern, samples, None, steps, sample_rate)
+}
+
+/// Render a pattern's song to stereo frames, once through to its last entry
+///
+/// samples: one sample per track, in track order
+///
+/// sample_rate: frames per second of the output
+///
+/// returns an error if there is no song, the samples are empty, or kira cant play
+pub fn render_song(
+    pattern: &Pattern,
+    samples: ActiveSamples,
+    sample_rate: u32,
+) -> Result<Vec<Frame>, Box<dyn Error>> {
+    if pattern.song.entries.is_empty() {
+        return Err("the pattern has no song".into());
+    }
+    let song = Song {
+        stop_at_end: true,
+        ..pattern.song.clone()
+    };
+    let steps = song.steps(&pattern.bank);
+    render_steps(pattern, samples, Some(song), steps, sample_rate)
+}
+
+/// render a number of steps of the pattern that plays first, or a song
+fn render_steps(
+    pattern: &Pattern,
+    samples: ActiveSamples,
+    song: Option<Song>,
+    total_steps: usize,
+    sample_rate: u32,
+) -> Result<Vec<Frame>, Box<dyn Error>> {
+    let manager = AudioManager::<MockBackend>::new(AudioManagerSettings {
+        backend_settings: MockBackendSettings { sample_rate },
+        capacities: capacities(),
+        ..Default::default()
+    })
+    .map_err(|_| "cant start the mock audio backend")?;
+    let mut player = PlayBack::with_manager(manager, samples)?;
+    // the mixer tracks and their effects get made in with_manager, but kira only adds them
+    // at the start of the next block it processes. the pattern's effect settings below would
+    // go to effects that arent in the mixer yet and get lost, so process one frame to put
+    // them in first. the clock hasnt started so the frame is silent and doesnt get kept
+    player.process(1);
+    for (slot, sequence) in pattern.bank.iter().enumerate() {
+        player.set_sequence(slot, sequence.get_sequence());
+    }
+    player.cue(pattern.play);
+    player.set_song(song);
+    player.set_swing(pattern.swing, pattern.track_swing.clone());
+    player.set_seed(pattern.seed);
+    player.set_curve(pattern.curve.clone());
+    player.set_accents(pattern.accents, pattern.track_accents.clone())?;
+    player.set_mix(pattern.mix.clone())?;
+    player.set_effects(pattern.effects.clone(), pattern.bus_effects)?;
+    player.set_choke(pattern.choke.clone());
+    player.schedule_ahead()?;
+
+    // kira counts the frame a clock starts on towards its first tick, which would put every
+    // step after the first a frame early. so start the clock stood still and give it the
+    // tempo a frame later
+    player.set_tempo(0, pattern.divisions)?;
+    player.set_mute(false)?;
+    let mut frames = player.process(1);
+    player.set_tempo(pattern.tempo, pattern.divisions)?;
+
+    let frames_per_step =
+        sample_rate as f64 * 60.0 / (pattern.tempo as f64 * pattern.divisions as f64);
+    let length = (total_steps as f64 * frames_per_step).round() as usize;
+    frames.reserve(length);
+
+    // same as a sound card would: queue up the next steps, then process a block
+    let block_size = (sample_rate / BLOCKS_PER_SECOND).max(1) as usize;
+    while frames.len() < length {
+        player.schedule_ahead()?;
+        let block = block_size.min(length - frames.len());
+        frames.extend(player.process(block));
+    }
+
+    Ok(frames)
+}
+
+/// Write frames to a 16 bit stereo wav file, anything past full scale gets clipped
+pub fn write_wav(path: &Path, frames: &[Frame], sample_rate: u32) -> Result<(), Box<dyn Error>> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let data_len = frames.len() as u32 * 4;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&2u16.to_le_bytes())?; // channels
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 4).to_le_bytes())?; // byte rate
+    out.write_all(&4u16.to_le_bytes())?; // block align
+    out.write_all(&16u16.to_le_bytes())?; // bits per sample
+
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    for frame in frames {
+        for s in [frame.left, frame.right] {
+            let s = (s.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+            out.write_all(&s.to_le_bytes())?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, render_song};
+    use crate::effects::Knob;
+    use crate::pattern::Pattern;
+    use crate::samples::Sample;
+    use crate::sequencer::{AccentLevel, Condition, Lock, Sequence, Step};
+    use crate::song::SongEntry;
+    use kira::dsp::Frame;
+    use kira::sound::static_sound::{StaticSoundData, StaticSoundSettings};
+    use std::sync::Arc;
+
+    /// frames a second the tests render at. 150 bpm, 4 to a beat is 100 frames a step
+    const RATE: u32 = 1000;
+
+    /// a sample thats one full scale click then silence
+    fn click() -> Sample {
+        let mut frames = vec![Frame::ZERO; 8];
+        frames[0] = Frame::from_mono(0.5);
+        let data = StaticSoundData {
+            sample_rate: RATE,
+            frames: Arc::from(frames),
+            settings: StaticSoundSettings::default(),
+        };
+        Sample::new("click", data)
+    }
+
+    /// every frame that isnt silent on the left, and how loud it is
+    fn onsets(frames: &[Frame]) -> Vec<(usize, f32)> {
+        frames
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.left.abs() > 1e-4)
+            .map(|(i, f)| (i, f.left))
+            .collect()
+    }
+
+    /// render loops of a pattern with a click on every track, and get where the clicks are
+    fn render_clicks(p: &Pattern, loops: u32) -> Vec<(usize, f32)> {
+        let samples = vec![click(); p.samples.len()];
+        onsets(&render(p, samples, loops, RATE).unwrap())
+    }
+
+    /// hits land where they should, give or take the frame kira's clock rounds them to
+    fn assert_hits(hits: &[(usize, f32)], expected: &[usize]) {
+        let at: Vec<usize> = hits.iter().map(|h| h.0).collect();
+        assert_eq!(at.len(), expected.len(), "{:?} vs {:?}", at, expected);
+        for (hit, want) in at.iter().zip(expected) {
+            assert!(hit.abs_diff(*want) <= 1, "{:?} vs {:?}", at, expected);
+        }
+    }
+
+    /// how loud every hit is
+    fn peaks(hits: &[(usize, f32)]) -> Vec<f32> {
+        hits.iter().map(|h| h.1).collect()
+    }
+
+    #[test]
+    fn length_and_timing() {
+        let mut p = Pattern::new(1, 4, 4, 150);
+        p.sequence_mut()
+            .set_step(0, 0, AccentLevel::Regular)
+            .unwrap();
+        p.sequence_mut()
+            .set_step(0, 2, AccentLevel::Regular)
+            .unwrap();
+
+        let frames = render(&p, vec![click()], 2, RATE).unwrap();
+        assert_eq!(frames.len(), 800);
+        assert_hits(&onsets(&frames), &[0, 200, 400, 600]);
+    }
+
+    #[test]
+    fn no_drift_on_uneven_steps() {
+        // 7 bpm * 1 to a beat does not divide a second evenly
+        let mut p = Pattern::new(1, 1, 1, 7);
+        p.sequence_mut()
+            .set_step(0, 0, AccentLevel::Regular)
+            .unwrap();
+
+        let hits = render_clicks(&p, 30);
+        let first = hits[0].0;
+        for (n, hit) in hits.iter().enumerate() {
+            let expected = (n as f64 * 60_000.0 / 7.0).round() as usize + first;
+            assert!(
+                hit.0.abs_diff(expected) <= 1,
+                "step {} landed at {}",
+                n,
+                hit.0
+            );
+        }
+    }
+
+    #[test]
+    fn no_drift_over_thousands_of_steps() {
+        // 130 bpm, 4 to a beat: 115.38.. frames a step
+        let mut p = Pattern::new(1, 4, 4, 130);
+        for step in 0..4 {
+            p.sequence_mut()
+                .set_step(0, step, AccentLevel::Regular)
+                .unwrap();
+        }
+
+        let hits = render_clicks(&p, 1250);
+        assert_eq!(hits.len(), 5000);
+        let frames_per_step = 60_000.0 / (130.0 * 4.0);
+        let worst = hits
+            .iter()
+            .enumerate()
+            .map(|(n, hit)| (hit.0 as f64 - n as f64 * frames_per_step).abs())
+            .fold(0.0, f64::max);
+        assert!(worst <= 1.0, "a step was {} frames off the grid", worst);
+    }
+
+    #[test]
+    fn tracks_wrap_on_their_own() {
+        let mut p = Pattern::new(2, 4, 4, 150);
+        p.sequence_mut().set_track_steps(1, 3).unwrap();
+        p.sequence_mut()
+            .set_step(0, 0, AccentLevel::Regular)
+            .unwrap();
+        p.sequence_mut()
+            .set_step(1, 0, AccentLevel::Regular)
+            .unwrap();
+
+        // track 0 on steps 0, 4, 8 and track 1 on steps 0, 3, 6, 9, both on step 0
+        assert_hits(&render_clicks(&p, 3), &[0, 300, 400, 600, 800, 900]);
+    }
+
+    #[test]
+    fn swing() {
+        let mut p = Pattern::new(2, 4, 4, 150);
+        p.swing = 50;
+        p.track_swing[1] = Some(25);
+        for step in 0..4 {
+            p.sequence_mut()
+                .set_step(0, step, AccentLevel::Regular)
+                .unwrap();
+        }
+        p.sequence_mut()
+            .set_step(1, 1, AccentLevel::Regular)
+            .unwrap();
+
+        // every second step half a step late, track 1 only a quarter
+        assert_hits(&render_clicks(&p, 1), &[0, 125, 150, 200, 350]);
+    }
+
+    #[test]
+    fn ratchets() {
+        let mut p = Pattern::new(1, 4, 4, 150);
+        p.swing = 50;
+        let step = |ratchet, decay| Step {
+            ratchet,
+            decay,
+            ..AccentLevel::Regular.into()
+        };
+        p.sequence_mut().put_step(0, 0, step(4, true)).unwrap();
+        // swung, so the hits share whats left of the step
+        p.sequence_mut().put_step(0, 1, step(2, false)).unwrap();
+
+        let hits = render_clicks(&p, 1);
+        assert_hits(&hits, &[0, 25, 50, 75, 150, 175]);
+        let peaks = peaks(&hits);
+        for (peak, want) in peaks.iter().zip([0.5, 0.375, 0.25, 0.125, 0.5, 0.5]) {
+            assert!((peak - want).abs() < 1e-3, "{:?}", peaks);
+        }
+    }
+
+    #[test]
+    fn nudges() {
+        let mut p = Pattern::new(1, 4, 4, 150);
+        let nudged = |nudge| Step {
+            nudge,
+            ..AccentLevel::Regular.into()
+        };
+        p.sequence_mut().put_step(0, 0, nudged(-3)).unwrap();
+        p.sequence_mut().put_step(0, 1, nudged(-6)).unwrap();
+        p.sequence_mut().put_step(0, 2, nudged(6)).unwrap();
+
+        // the very first step cant go before the start, after that its an 8th of a step
+        // early and lands in the loop before, even the one after the last loop
+        assert_hits(&render_clicks(&p, 2), &[0, 75, 225, 388, 475, 625, 788]);
+    }
+
+    #[test]
+    fn locks() {
+        let mut p = Pattern::new(1, 4, 4, 150);
+        let locked = |lock, value| {
+            let mut step: Step = AccentLevel::Regular.into();
+            step.locks.set(lock, Some(value)).unwrap();
+            step
+        };
+        p.sequence_mut()
+            .put_step(0, 0, locked(Lock::Gain, -6))
+            .unwrap();
+        p.sequence_mut()
+            .put_step(0, 1, locked(Lock::Pan, -100))
+            .unwrap();
+        // the click is at the very start of the sample, starting halfway skips it
+        p.sequence_mut()
+            .put_step(0, 2, locked(Lock::Start, 50))
+            .unwrap();
+
+        let frames = render(&p, vec![click()], 1, RATE).unwrap();
+        let hits = onsets(&frames);
+        assert_hits(&hits, &[0, 100]);
+        assert!((hits[0].1 - 0.25).abs() < 0.01, "{:?}", hits);
+        assert!(frames[hits[1].0].right.abs() < 1e-4);
+    }
+
+    #[test]
+    fn songs() {
+        let mut p = Pattern::new(1, 2, 4, 150);
+        p.bank[0].set_step(0, 0, AccentLevel::Regular).unwrap();
+        p.bank[1].set_track_steps(0, 3).unwrap();
+        p.bank[1].set_step(0, 2, AccentLevel::Regular).unwrap();
+        p.song.entries = vec![
+            SongEntry {
+                pattern: 1,
+                repeats: 1,
+            },
+            SongEntry {
+                pattern: 0,
+                repeats: 2,
+            },
+            SongEntry {
+                pattern: 1,
+                repeats: 1,
+            },
+        ];
+        // the loop doesnt matter when bouncing, it plays start to end
+        p.song.loop_points = Some((0, 0));
+
+        let frames = render_song(&p, vec![click()], RATE).unwrap();
+        assert_eq!(frames.len(), 1000);
+        assert_hits(&onsets(&frames), &[200, 300, 500, 900]);
+
+        p.song.entries.clear();
+        assert!(render_song(&p, vec![click()], RATE).is_err());
+    }
+
+    #[test]
+    fn same_dice_every_render() {
+        let mut p = Pattern::new(1, 16, 4, 150);
+        for step in 0..16 {
+            p.sequence_mut()
+                .put_step(
+                    0,
+                    step,
+                    Step {
+                        velocity: AccentLevel::Regular.velocity(),
+                        probability: 50,
+                        condition: Condition::Always,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+        p.seed = 99;
+
+        let first = render_clicks(&p, 4);
+        assert!(first.len() > 10 && first.len() < 54, "{} hits", first.len());
+        assert_eq!(first, render_clicks(&p, 4));
+        p.seed = 100;
+        assert_ne!(first, render_clicks(&p, 4));
+    }
+
+    #[test]
+    fn accent_gains() {
+        let mut p = Pattern::new(1, 4, 1, 60);
+        p.sequence_mut().set_step(0, 1, AccentLevel::Soft).unwrap();
+        p.sequence_mut()
+            .set_step(0, 2, AccentLevel::Regular)
+            .unwrap();
+        p.sequence_mut().set_step(0, 3, AccentLevel::Loud).unwrap();
+
+        let peaks = peaks(&render_clicks(&p, 1));
+        assert_eq!(peaks.len(), 3);
+        // -6dB and +6dB are about half and double
+        assert!((peaks[0] / peaks[1] - 0.5).abs() < 0.01);
+        assert!((peaks[2] / peaks[1] - 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn accent_settings() {
+        let mut p = Pattern::new(2, 2, 1, 60);
+        p.sequence_mut().set_step(0, 0, AccentLevel::Loud).unwrap();
+        p.sequence_mut().set_step(1, 0, AccentLevel::Loud).unwrap();
+        p.sequence_mut()
+            .set_step(0, 1, AccentLevel::Regular)
+            .unwrap();
+        p.accents = "0,-6,0".parse().unwrap();
+        // track 1 loud hits go 6dB down, right from the first frame
+        p.track_accents[1] = Some("0,0,-6".parse().unwrap());
+
+        let peaks = peaks(&render_clicks(&p, 1));
+        // both tracks loud at once, then a regular hit 6dB under the curve
+        assert!((peaks[0] - 1.5).abs() < 0.02, "{:?}", peaks);
+        assert!((peaks[1] - 0.25).abs() < 0.01, "{:?}", peaks);
+    }
+
+    #[test]
+    fn sample_gain() {
+        let mut p = Pattern::new(2, 2, 1, 60);
+        p.sequence_mut()
+            .set_step(0, 0, AccentLevel::Regular)
+            .unwrap();
+        p.sequence_mut()
+            .set_step(1, 1, AccentLevel::Regular)
+            .unwrap();
+        let mut quiet = click();
+        quiet.gain = -6.0;
+
+        let frames = render(&p, vec![quiet, click()], 1, RATE).unwrap();
+        let hits = onsets(&frames);
+        assert_hits(&hits, &[0, 1000]);
+        assert!((hits[0].1 - 0.25).abs() < 0.01, "{:?}", hits);
+        assert!((hits[1].1 - 0.5).abs() < 0.01, "{:?}", hits);
+    }
+
+    #[test]
+    fn mixer() {
+        let mut p = Pattern::new(3, 3, 1, 60);
+        p.sequence_mut()
+            .set_step(0, 0, AccentLevel::Regular)
+            .unwrap();
+        p.sequence_mut()
+            .set_step(1, 1, AccentLevel::Regular)
+            .unwrap();
+        p.sequence_mut()
+            .set_step(2, 2, AccentLevel::Regular)
+            .unwrap();
+        p.mix[0].volume = -6.0;
+        p.mix[1].pan = 100;
+        p.mix[2].mute = true;
+        // where theres something on the right
+        let right = |frames: &[Frame]| -> Vec<(usize, f32)> {
+            frames
+                .iter()
+                .enumerate()
+                .filter(|(_, f)| f.right.abs() > 1e-4)
+                .map(|(i, f)| (i, f.right))
+                .collect()
+        };
+
+        let frames = render(&p, vec![click(); 3], 1, RATE).unwrap();
+        let hits = onsets(&frames);
+        assert_hits(&hits, &[0]);
+        assert!((hits[0].1 - 0.25).abs() < 0.01, "{:?}", hits);
+        // hard right, the left side is empty
+        let hard_right: Vec<(usize, f32)> = right(&frames)
+            .into_iter()
+            .filter(|h| frames[h.0].left == 0.0 && h.1 > 0.5)
+            .collect();
+        assert_hits(&hard_right, &[1000]);
+
+        // soloing mutes everything else, even with the muted one soloed too
+        p.mix[1].solo = true;
+        p.mix[2].solo = true;
+        let frames = render(&p, vec![click(); 3], 1, RATE).unwrap();
+        assert!(onsets(&frames).is_empty());
+        assert_hits(&right(&frames), &[1000]);
+    }
+
+    #[test]
+    fn effects() {
+        let mut p = Pattern::new(1, 4, 4, 150);
+        p.sequence_mut()
+            .set_step(0, 0, AccentLevel::Regular)
+            .unwrap();
+        let peak = |p: &Pattern| peaks(&render_clicks(p, 1)).into_iter().fold(0.0, f32::max);
+
+        // all wet, no feedback: the click comes out 2 steps late and only then
+        p.effects[0].set(Knob::Delay, 2).unwrap();
+        p.effects[0].set(Knob::Feedback, 0).unwrap();
+        p.effects[0].set(Knob::Wet, 100).unwrap();
+        let loud: Vec<(usize, f32)> = render_clicks(&p, 1)
+            .into_iter()
+            .filter(|h| h.1 > 0.1)
+            .collect();
+        assert_hits(&loud, &[200]);
+
+        // soft clipping squashes it, the filter all the way down takes the click out
+        p.effects[0] = Default::default();
+        p.effects[0].set(Knob::Drive, 24).unwrap();
+        // 0.5 driven up 24dB clips to 0.888, then kira turns it back down by the drive
+        assert!((peak(&p) - 0.056).abs() < 0.001, "{}", peak(&p));
+        p.effects[0] = Default::default();
+        p.effects[0].set(Knob::Cutoff, 0).unwrap();
+        assert!(peak(&p) < 0.1, "{}", peak(&p));
+
+        // the reverb bus rings on long after the click, the dry track doesnt
+        p.effects[0] = Default::default();
+        let tail = |p: &Pattern| render_clicks(p, 1).iter().filter(|h| h.0 >= 100).count();
+        assert_eq!(tail(&p), 0);
+        p.effects[0].set(Knob::ReverbSend, 100).unwrap();
+        assert!(tail(&p) > 100);
+    }
+
+    #[test]
+    fn choke_groups() {
+        let mut p = Pattern::new(2, 4, 4, 150);
+        p.sequence_mut()
+            .set_step(0, 0, AccentLevel::Regular)
+            .unwrap();
+        p.sequence_mut()
+            .set_step(1, 2, AccentLevel::Regular)
+            .unwrap();
+        // an open hat that rings right through the pattern, and a closed one
+        let ringing = StaticSoundData {
+            sample_rate: RATE,
+            frames: Arc::from(vec![Frame::from_mono(0.5); 1000]),
+            settings: StaticSoundSettings::default(),
+        };
+        let ringing = Sample::new("open hat", ringing);
+        let ringing_at = |p: &Pattern, frame: usize| {
+            let frames = render(p, vec![ringing.clone(), click()], 1, RATE).unwrap();
+            frames[frame].left > 0.1
+        };
+        assert!(ringing_at(&p, 300));
+
+        p.choke = vec![Some(1), Some(1)];
+        assert!(ringing_at(&p, 150));
+        assert!(!ringing_at(&p, 250));
+
+        // the closed hat gets queued first but plays after the open one, it still cuts it off
+        let mut p = Pattern::new(2, 4, 4, 150);
+        p.sequence_mut()
+            .set_step(0, 1, AccentLevel::Regular)
+            .unwrap();
+        let early = Step {
+            nudge: -12,
+            ..AccentLevel::Regular.into()
+        };
+        p.sequence_mut().put_step(1, 1, early).unwrap();
+        p.choke = vec![Some(1), Some(1)];
+        let frames = render(&p, vec![click(), ringing.clone()], 1, RATE).unwrap();
+        assert!(frames[75].left > 0.1);
+        assert!(frames[150].left < 0.1);
+    }
+
+    #[test]
+    fn velocity_curve() {
+        let mut p = Pattern::new(1, 2, 1, 60);
+        let hit = |velocity| Step {
+            velocity,
+            ..Default::default()
+        };
+        // halfway between the soft and regular presets is -3dB on the default curve
+        p.sequence_mut().put_step(0, 0, hit(72)).unwrap();
+        p.sequence_mut().put_step(0, 1, hit(96)).unwrap();
+        let curved = peaks(&render_clicks(&p, 1));
+        assert!((curved[0] / curved[1] - 0.708).abs() < 0.01, "{:?}", curved);
+
+        p.curve = "1:-12 127:-12".parse().unwrap();
+        let peaks = peaks(&render_clicks(&p, 1));
+        assert!((peaks[0] - peaks[1]).abs() < 0.001, "{:?}", peaks);
+        assert!((peaks[1] - 0.125).abs() < 0.01, "{:?}", peaks);
+    }
+}
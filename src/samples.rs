@@ -1,25 +1,682 @@
+/**
+Loading the sample bank.
+
+Samples are wav, flac, ogg or mp3 files, whatever case the extension is in. Ones that wont
+decode get skipped with a warning, the rest of the kit still loads.
+
+Samples live in a library directory, `--samples` on the command line. Without one its
+`./samples` if theres one there, otherwise `$XDG_DATA_HOME/fungus/samples`
+(`~/.local/share/fungus/samples`).
+
+Every subdirectory of the library is a kit, and the samples straight in the library are one
+too, called `/`. Samples load in natural order of their file names, so `kick2.wav` comes
+before `kick10.wav` and every machine gives the same tracks the same sounds. A sample's name
+is its file name without the extension, it labels the track.
+
+A kit can have a `manifest` file to set it up, one sample per line in track order:
+
+```text
+# file, then optional settings
+kick.wav name=BD gain=-3
+closed-hat.wav name=CH choke=1
+open-hat.wav name=OH choke=1
+"ride 2.wav" name="big ride"
+```
+
+- `name` labels the track instead of the file name
+- a file or name with spaces in it goes in double quotes
+- `gain` in dB, how loud the sample plays before anything else
+- `choke` is the choke group new patterns put the track in, see `playback`
+
+Samples that arent in the manifest go after the ones that are, in natural order. Files in the
+manifest that arent there get skipped with a warning, like ones that wont decode.
+
+The library gets watched while fungus plays, the kit thats playing gets loaded again when
+anything in it changes. Changes to other kits get left alone. Its
+samples get matched up with the ones it had by name, so tracks keep their steps and settings
+when their sample gets edited. A sample that wont decode while its being saved keeps playing
+what it had before, only tracks on a sample whose file is gone go too. New samples get new
+tracks on the end.
+*/
 use kira::sound::static_sound::{StaticSoundData, StaticSoundSettings};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::cmp::Ordering;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
 use std::{error::Error, ffi::OsStr, fs};
 
+use crate::playback::CHOKE_GROUPS;
+
+/// the kit made of the samples straight in the library
+pub static ROOT_KIT: &str = "/";
+
+static MANIFEST: &str = "manifest";
+
+/// the file extensions that get loaded as samples, in lower case
+static EXTENSIONS: [&str; 4] = ["wav", "flac", "ogg", "mp3"];
+
+/// A sound and what its called
+#[derive(Clone)]
+pub struct Sample {
+    pub name: String,
+    pub data: StaticSoundData,
+    /// dB it plays at before anything else
+    pub gain: f64,
+    /// the choke group new patterns put its track in
+    pub choke: Option<u8>,
+    /// the file it got loaded from, None if it wasnt
+    pub path: Option<PathBuf>,
+}
+
+impl Sample {
+    pub fn new(name: &str, data: StaticSoundData) -> Sample {
+        Sample {
+            name: name.to_string(),
+            data,
+            gain: 0.0,
+            choke: None,
+            path: None,
+        }
+    }
+}
+
 /// bank of active samples
-pub type ActiveSamples = Vec<StaticSoundData>;
+pub type ActiveSamples = Vec<Sample>;
 
-/// Load up the samples innit
-pub fn load() -> Result<ActiveSamples, Box<dyn Error>> {
-    let mut samples: Vec<StaticSoundData> = vec![];
+/// Where the kits are
+pub struct Library {
+    pub dir: PathBuf,
+}
 
-    let paths = fs::read_dir("./samples")?;
+impl Library {
+    pub fn new(dir: &Path) -> Library {
+        Library {
+            dir: dir.to_path_buf(),
+        }
+    }
 
-    for file in paths {
-        let path = match file {
-            Ok(f) => f.path(),
-            Err(_) => continue,
+    /// the library when theres no `--samples`: `./samples` if theres one there, otherwise
+    /// the XDG data directory
+    pub fn find() -> Library {
+        let local = Path::new("./samples");
+        if local.is_dir() {
+            return Library::new(local);
+        }
+        let data = match env::var_os("XDG_DATA_HOME") {
+            Some(d) if !d.is_empty() => PathBuf::from(d),
+            _ => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".local/share"),
         };
+        Library::new(&data.join("fungus").join("samples"))
+    }
+
+    /// every kit with samples in it, `ROOT_KIT` first then the subdirectories in natural order
+    ///
+    /// returns an error if the library cant be read
+    pub fn kits(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut kits = vec![];
+        if !sample_files(&self.dir)?.is_empty() {
+            kits.push(ROOT_KIT.to_string());
+        }
+        let mut dirs: Vec<String> = fs::read_dir(&self.dir)?
+            .filter_map(|f| f.ok().map(|f| f.path()))
+            .filter(|p| p.is_dir() && sample_files(p).is_ok_and(|f| !f.is_empty()))
+            .filter_map(|p| Some(p.file_name()?.to_string_lossy().into_owned()))
+            .collect();
+        dirs.sort_by(|a, b| natural_cmp(a, b));
+        kits.extend(dirs);
+        Ok(kits)
+    }
+
+    /// the first kit, for when nothing says which
+    ///
+    /// returns an error if the library has no samples
+    pub fn first_kit(&self) -> Result<String, Box<dyn Error>> {
+        self.kits()?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("theres no samples in {}", self.dir.display()).into())
+    }
+
+    /// Load up the samples in a kit innit
+    ///
+    /// returns the samples and a warning for every file that wouldnt decode or isnt there, or
+    /// an error if the kit or its manifest cant be read or none of its samples decode
+    pub fn load(&self, kit: &str) -> Result<(ActiveSamples, Vec<String>), Box<dyn Error>> {
+        self.reload(kit, &[])
+    }
 
-        if !path.is_dir() && path.extension() == Some(OsStr::new("wav")) {
-            let sample = StaticSoundData::from_file(path, StaticSoundSettings::default())?;
+    /// Load a kit again after its files changed, files that wont decode keep the sample they
+    /// had in old, eg: while an editor is halfway through saving one
+    ///
+    /// returns the samples and a warning for every file that wouldnt decode or isnt there, or
+    /// an error if the kit or its manifest cant be read or none of its samples decode
+    pub fn reload(
+        &self,
+        kit: &str,
+        old: &[Sample],
+    ) -> Result<(ActiveSamples, Vec<String>), Box<dyn Error>> {
+        let dir = self.kit_dir(kit);
+        let mut files = sample_files(&dir).map_err(|e| format!("cant open kit {}: {}", kit, e))?;
+        let manifest = dir.join(MANIFEST);
+        let entries = if manifest.is_file() {
+            parse_manifest(&fs::read_to_string(&manifest)?)
+                .map_err(|e| format!("{}: {}", manifest.display(), e))?
+        } else {
+            vec![]
+        };
+
+        let mut samples = vec![];
+        let mut warnings = vec![];
+        // the manifest's files, a file can have been deleted since and its track goes like
+        // any other sample thats gone
+        let mut listed = vec![];
+        for entry in entries {
+            match files
+                .iter()
+                .position(|f| f.file_name() == Some(OsStr::new(&entry.file)))
+            {
+                Some(at) => listed.push((files.remove(at), entry)),
+                None => warnings.push(format!("{}: theres no {}", manifest.display(), entry.file)),
+            }
+        }
+        let mut load = |path: &Path| match load_file(path) {
+            Ok(sample) => Some(sample),
+            Err(e) => match old.iter().find(|s| s.path.as_deref() == Some(path)) {
+                Some(sample) => {
+                    warnings.push(format!("kept the old {}: {}", path.display(), e));
+                    Some(sample.clone())
+                }
+                None => {
+                    warnings.push(format!("skipped {}: {}", path.display(), e));
+                    None
+                }
+            },
+        };
+        for (path, entry) in listed {
+            let Some(mut sample) = load(&path) else {
+                continue;
+            };
+            if let Some(name) = entry.name {
+                sample.name = name;
+            }
+            sample.gain = entry.gain;
+            sample.choke = entry.choke;
             samples.push(sample);
         }
+        samples.extend(files.iter().filter_map(|path| load(path)));
+        if samples.is_empty() {
+            let mut e = format!("none of the samples in kit {} would load", kit);
+            for warning in warnings {
+                e.push_str(&format!("\n{}", warning));
+            }
+            return Err(e.into());
+        }
+        Ok((samples, warnings))
+    }
+
+    /// where a kit's samples are
+    pub fn kit_dir(&self, kit: &str) -> PathBuf {
+        if kit == ROOT_KIT {
+            self.dir.clone()
+        } else {
+            self.dir.join(kit)
+        }
+    }
+}
+
+/// Tells when anything in a library changes
+pub struct Watcher {
+    /// stops watching when it goes
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+}
+
+impl Library {
+    /// start watching the library, kits and all
+    ///
+    /// returns an error if the library cant be watched, eg: it isnt there
+    pub fn watch(&self) -> Result<Watcher, Box<dyn Error>> {
+        let (tx, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        watcher.watch(&self.dir, RecursiveMode::Recursive)?;
+        Ok(Watcher {
+            _watcher: watcher,
+            events,
+        })
+    }
+}
+
+impl Watcher {
+    /// has anything in a kit changed since the last time it was asked, files only being read
+    /// dont count
+    ///
+    /// dir: where the kit is, from `Library::kit_dir`
+    pub fn changed(&self, dir: &Path) -> bool {
+        let mut changed = false;
+        while let Ok(event) = self.events.try_recv() {
+            changed |= event.is_ok_and(|e| {
+                !matches!(e.kind, EventKind::Access(_)) && e.paths.iter().any(|p| in_kit(p, dir))
+            });
+        }
+        changed
+    }
+}
+
+/// Match the tracks up with a kit thats been loaded again, by the names of their samples
+///
+/// old: the kit's samples before
+///
+/// new: the kit's samples now
+///
+/// map: which of the old samples each track plays
+///
+/// returns which of the new samples each track plays, and the track each one was before,
+/// None for new ones. samples with the same name get matched up in order. if every track
+/// went theres one new one on the first sample
+pub fn rematch(old: &[Sample], new: &[Sample], map: &[usize]) -> (Vec<usize>, Vec<Option<usize>>) {
+    // the nth sample with a name is still the nth with it
+    let nth = |samples: &[Sample], i: usize| {
+        samples[..i]
+            .iter()
+            .filter(|s| s.name == samples[i].name)
+            .count()
+    };
+    let moved: Vec<Option<usize>> = (0..old.len())
+        .map(|i| {
+            (0..new.len())
+                .filter(|&j| new[j].name == old[i].name)
+                .nth(nth(old, i))
+        })
+        .collect();
+
+    let (mut samples, mut from) = (vec![], vec![]);
+    for (track, &sample) in map.iter().enumerate() {
+        if let Some(Some(j)) = moved.get(sample) {
+            samples.push(*j);
+            from.push(Some(track));
+        }
+    }
+    for j in (0..new.len()).filter(|j| !moved.contains(&Some(*j))) {
+        samples.push(j);
+        from.push(None);
+    }
+    // the samples that are left might not have had a track
+    if samples.is_empty() && !new.is_empty() {
+        samples.push(0);
+        from.push(None);
+    }
+    (samples, from)
+}
+
+/// a list rearranged to go with tracks thats moved
+///
+/// from: the index each item was at before, None for new ones which get `new`
+pub fn remap<T: Clone>(items: &[T], from: &[Option<usize>], new: T) -> Vec<T> {
+    from.iter()
+        .map(|f| f.and_then(|i| items.get(i).cloned()).unwrap_or(new.clone()))
+        .collect()
+}
+
+/// a sample file, named after itself
+fn load_file(path: &Path) -> Result<Sample, Box<dyn Error>> {
+    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+    let data = StaticSoundData::from_file(path, StaticSoundSettings::default())?;
+    Ok(Sample {
+        path: Some(path.to_path_buf()),
+        ..Sample::new(&name, data)
+    })
+}
+
+/// one sample's line in a manifest
+#[derive(PartialEq, Debug)]
+struct ManifestEntry {
+    file: String,
+    name: Option<String>,
+    gain: f64,
+    choke: Option<u8>,
+}
+
+/// read a kit's manifest
+///
+/// returns an error with the offending line number if its malformed
+fn parse_manifest(text: &str) -> Result<Vec<ManifestEntry>, String> {
+    let mut entries = vec![];
+    for (num, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words = split_quoted(line).ok_or(format!("line {}: a quote isnt closed", num))?;
+        let mut words = words.into_iter();
+        let mut entry = ManifestEntry {
+            file: words.next().unwrap_or_default(),
+            name: None,
+            gain: 0.0,
+            choke: None,
+        };
+        for setting in words {
+            match setting.split_once('=') {
+                Some(("name", n)) if !n.is_empty() => entry.name = Some(n.to_string()),
+                Some(("gain", g)) => {
+                    entry.gain = match g.parse::<f64>() {
+                        Ok(g) if g.is_finite() => g,
+                        _ => return Err(format!("line {}: gain is in dB", num)),
+                    }
+                }
+                Some(("choke", c)) => {
+                    entry.choke = match c.parse::<u8>() {
+                        Ok(c) if (1..=CHOKE_GROUPS).contains(&c) => Some(c),
+                        _ => {
+                            return Err(format!(
+                                "line {}: choke groups go from 1 to {}",
+                                num, CHOKE_GROUPS
+                            ))
+                        }
+                    }
+                }
+                _ => return Err(format!("line {}: unknown setting '{}'", num, setting)),
+            }
+        }
+        if entries.iter().any(|e: &ManifestEntry| e.file == entry.file) {
+            return Err(format!("line {}: {} is in here twice", num, entry.file));
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// a line split up at its spaces, except the ones in double quotes. the quotes go, eg:
+/// `"open hat.wav" name="OH 2"` is `open hat.wav`, `name=OH 2`
+///
+/// returns None if a quote isnt closed
+fn split_quoted(line: &str) -> Option<Vec<String>> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    (!quoted).then_some(words)
+}
+
+/// the sample files in a directory, in natural order of their names
+fn sample_files(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|f| f.ok().map(|f| f.path()))
+        .filter(|p| !p.is_dir() && is_sample(p))
+        .collect();
+    files.sort_by(|a, b| {
+        natural_cmp(
+            &a.file_name().unwrap_or_default().to_string_lossy(),
+            &b.file_name().unwrap_or_default().to_string_lossy(),
+        )
+    });
+    Ok(files)
+}
+
+/// is a path one of a kit's files, or the kit itself. kits inside the root kit's directory
+/// arent part of it. both get resolved first, the library can be a relative path but the
+/// watcher gives absolute ones
+fn in_kit(path: &Path, dir: &Path) -> bool {
+    let (path, dir) = (resolved(path), resolved(dir));
+    path == dir || path.parent() == Some(&dir)
+}
+
+/// a path made absolute with its links followed, or as it is if it cant be. a file thats
+/// been deleted gets its directory resolved
+fn resolved(path: &Path) -> PathBuf {
+    if let Ok(p) = path.canonicalize() {
+        return p;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => match dir.canonicalize() {
+            Ok(dir) => dir.join(name),
+            Err(_) => path.to_path_buf(),
+        },
+        _ => path.to_path_buf(),
+    }
+}
+
+/// has one of the sample extensions, in any case
+fn is_sample(path: &Path) -> bool {
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+    EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e))
+}
+
+/// compare names the way people count: runs of digits by their number, the rest ignoring
+/// case. names that only differ in case or leading zeros still come out in the same order
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut x, mut y) = (a, b);
+    loop {
+        match (chunk(x), chunk(y)) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some((cx, rx)), Some((cy, ry))) => {
+                let digits = |c: &str| c.starts_with(|c: char| c.is_ascii_digit());
+                let order = if digits(cx) && digits(cy) {
+                    let (tx, ty) = (cx.trim_start_matches('0'), cy.trim_start_matches('0'));
+                    tx.len().cmp(&ty.len()).then_with(|| tx.cmp(ty))
+                } else {
+                    cx.to_lowercase().cmp(&cy.to_lowercase())
+                };
+                if order != Ordering::Equal {
+                    return order;
+                }
+                (x, y) = (rx, ry);
+            }
+        }
+    }
+}
+
+/// the next run of digits or non digits off the front of a name, and whats left
+fn chunk(s: &str) -> Option<(&str, &str)> {
+    let first = s.chars().next()?;
+    let end = s
+        .find(|c: char| c.is_ascii_digit() != first.is_ascii_digit())
+        .unwrap_or(s.len());
+    Some(s.split_at(end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        in_kit, is_sample, natural_cmp, parse_manifest, remap, rematch, Library, ManifestEntry,
+        Sample,
+    };
+    use crate::render::write_wav;
+    use kira::dsp::Frame;
+    use kira::sound::static_sound::{StaticSoundData, StaticSoundSettings};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[test]
+    fn natural_order() {
+        let mut names = vec![
+            "snare.wav",
+            "kick10.wav",
+            "Kick2.wav",
+            "kick1.wav",
+            "hat 03.wav",
+            "hat 3.wav",
+            "hat 20.wav",
+            "909.wav",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            vec![
+                "909.wav",
+                "hat 03.wav",
+                "hat 3.wav",
+                "hat 20.wav",
+                "kick1.wav",
+                "Kick2.wav",
+                "kick10.wav",
+                "snare.wav",
+            ]
+        );
+    }
+
+    #[test]
+    fn sample_extensions() {
+        for name in ["a.wav", "b.WAV", "c.Flac", "d.ogg", "e.MP3"] {
+            assert!(is_sample(Path::new(name)), "{}", name);
+        }
+        for name in ["manifest", "notes.txt", "wav", "f.wav.bak"] {
+            assert!(!is_sample(Path::new(name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn watching_one_kit() {
+        let library = Path::new("samples");
+        let kit = library.join("909");
+        assert!(in_kit(&kit.join("kick.wav"), &kit));
+        assert!(in_kit(&kit, &kit));
+        assert!(!in_kit(&library.join("808/kick.wav"), &kit));
+        assert!(in_kit(&library.join("kick.wav"), library));
+        assert!(!in_kit(&kit.join("kick.wav"), library));
+    }
+
+    #[test]
+    fn broken_files_get_skipped() {
+        let dir = std::env::temp_dir().join(format!("fungus-samples-{}", std::process::id()));
+        fs::create_dir_all(dir.join("kit")).unwrap();
+        write_wav(&dir.join("kit/kick.WAV"), &[Frame::from_mono(0.5)], 1000).unwrap();
+        fs::write(dir.join("kit/broken.flac"), "not a flac").unwrap();
+        fs::write(dir.join("kit/notes.txt"), "not a sample").unwrap();
+        fs::write(dir.join("kit/manifest"), "kick.WAV name=BD gain=-3\n").unwrap();
+
+        let library = Library::new(&dir);
+        assert_eq!(library.kits().unwrap(), vec!["kit".to_string()]);
+        let (samples, warnings) = library.load("kit").unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].name, "BD");
+        assert_eq!(samples[0].gain, -3.0);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("broken.flac"), "{:?}", warnings);
+
+        // halfway through being saved it keeps what it had
+        fs::write(dir.join("kit/kick.WAV"), "").unwrap();
+        let (again, warnings) = library.reload("kit", &samples).unwrap();
+        assert_eq!(again.len(), 1);
+        assert_eq!(again[0].name, "BD");
+        assert_eq!(again[0].data.frames.len(), 1);
+        assert!(warnings[0].contains("kept the old"), "{:?}", warnings);
+        assert!(library.load("kit").is_err());
+
+        fs::remove_file(dir.join("kit/kick.WAV")).unwrap();
+        fs::remove_file(dir.join("kit/manifest")).unwrap();
+        let e = library.load("kit").err().unwrap().to_string();
+        assert!(e.contains("broken.flac"), "{}", e);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deleted_manifest_files() {
+        let dir = std::env::temp_dir().join(format!("fungus-manifest-{}", std::process::id()));
+        fs::create_dir_all(dir.join("kit")).unwrap();
+        for name in ["kick.wav", "snare.wav"] {
+            write_wav(&dir.join("kit").join(name), &[Frame::from_mono(0.5)], 1000).unwrap();
+        }
+        fs::write(
+            dir.join("kit/manifest"),
+            "snare.wav name=SD\nkick.wav name=BD\n",
+        )
+        .unwrap();
+
+        let library = Library::new(&dir);
+        let (samples, _) = library.load("kit").unwrap();
+        assert_eq!(samples.len(), 2);
+
+        // the snare goes like a file thats not in the manifest would
+        fs::remove_file(dir.join("kit/snare.wav")).unwrap();
+        let (again, warnings) = library.reload("kit", &samples).unwrap();
+        assert_eq!(again.len(), 1);
+        assert_eq!(again[0].name, "BD");
+        assert!(warnings[0].contains("snare.wav"), "{:?}", warnings);
+
+        // the deleted file is still in the kit, however the library's path is written
+        let kit = dir.join("kit/../kit");
+        assert!(in_kit(&dir.join("kit/snare.wav"), &kit));
+        assert!(!in_kit(&dir.join("snare.wav"), &kit));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reloaded_kits_keep_their_tracks() {
+        let kit = |names: &[&str]| -> Vec<Sample> {
+            let data = StaticSoundData {
+                sample_rate: 1000,
+                frames: Arc::from(vec![]),
+                settings: StaticSoundSettings::default(),
+            };
+            names.iter().map(|n| Sample::new(n, data.clone())).collect()
+        };
+        let old = kit(&["hat", "kick", "kick", "snare"]);
+
+        // the snare went, a clap came and the second kick moved
+        let new = kit(&["clap", "hat", "kick", "kick", "rim"]);
+        let (samples, from) = rematch(&old, &new, &[1, 2, 3, 0]);
+        assert_eq!(samples, vec![2, 3, 1, 0, 4]);
+        assert_eq!(from, vec![Some(0), Some(1), Some(3), None, None]);
+
+        // two tracks on the one sample both stay on it
+        let (samples, from) = rematch(&old, &old, &[0, 0, 3]);
+        assert_eq!(samples, vec![0, 0, 3]);
+        assert_eq!(from, vec![Some(0), Some(1), Some(2)]);
+
+        // every track was on a sample thats gone, the one left has no track yet
+        let (samples, from) = rematch(&old[..2], &old[1..2], &[0, 0]);
+        assert_eq!(samples, vec![0]);
+        assert_eq!(from, vec![None]);
+
+        assert_eq!(
+            remap(&[5, 6, 7], &[Some(2), None, Some(0)], 0),
+            vec![7, 0, 5]
+        );
+    }
+
+    #[test]
+    fn manifests() {
+        let entries = parse_manifest("# a kit\nkick.wav name=BD gain=-3\n\nopen hat.wav choke=2\n");
+        assert!(entries.is_err());
+        let entries = parse_manifest("kick.wav name=BD gain=-3\n\nopen-hat.wav choke=2\n");
+        assert_eq!(
+            entries,
+            Ok(vec![
+                ManifestEntry {
+                    file: "kick.wav".to_string(),
+                    name: Some("BD".to_string()),
+                    gain: -3.0,
+                    choke: None,
+                },
+                ManifestEntry {
+                    file: "open-hat.wav".to_string(),
+                    name: None,
+                    gain: 0.0,
+                    choke: Some(2),
+                },
+            ])
+        );
+        // spaces go in quotes
+        let entries = parse_manifest("\"open hat.wav\" name=\"OH 2\" choke=2\n").unwrap();
+        assert_eq!(entries[0].file, "open hat.wav");
+        assert_eq!(entries[0].name, Some("OH 2".to_string()));
+        assert!(parse_manifest("\"open hat.wav choke=2").is_err());
+
+        assert!(parse_manifest("kick.wav choke=5").is_err());
+        assert!(parse_manifest("kick.wav gain=loud").is_err());
+        assert!(parse_manifest("kick.wav\nkick.wav").is_err());
     }
-    Ok(samples as ActiveSamples)
 }
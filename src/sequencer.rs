@@ -1,3 +1,19 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// the most loops an Every condition counts over
+pub const MAX_EVERY: u8 = 8;
+
+/// the most hits a ratchet squeezes into one step
+pub const MAX_RATCHET: u8 = 4;
+
+/// nudges move a step in 24ths of a step
+pub const NUDGES_PER_STEP: i8 = 24;
+
+/// the furthest a step can be nudged either way, half a step
+pub const MAX_NUDGE: i8 = 12;
+
+/// Presets and ranges of velocity, what the hit keys set and what the ui shows
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum AccentLevel {
     /// no sound
@@ -7,18 +23,305 @@ pub enum AccentLevel {
     Loud,
 }
 
-/// A trck is a vec of trigger levels: 0 for silence, a positive int for how loud
-type Track = Vec<AccentLevel>;
+impl AccentLevel {
+    /// the velocity the preset sets a step to
+    pub fn velocity(&self) -> u8 {
+        match self {
+            AccentLevel::Silent => 0,
+            AccentLevel::Soft => 48,
+            AccentLevel::Regular => 96,
+            AccentLevel::Loud => MAX_VELOCITY,
+        }
+    }
+
+    /// which level a velocity is in, the inverse of `velocity`
+    pub fn of(velocity: u8) -> AccentLevel {
+        match velocity {
+            0 => AccentLevel::Silent,
+            1..=71 => AccentLevel::Soft,
+            72..=111 => AccentLevel::Regular,
+            _ => AccentLevel::Loud,
+        }
+    }
+}
+
+/// velocities go from 0, silent, up to this. same as midi
+pub const MAX_VELOCITY: u8 = 127;
+
+/// When a step gets to play, Elektron style trig conditions
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Condition {
+    Always,
+    /// only the first time round
+    First,
+    /// every time but the first
+    NotFirst,
+    /// the nth time round out of every so many, 1 indexed: 1 of 4 is the first of every 4
+    Every {
+        nth: u8,
+        of: u8,
+    },
+    /// only while fill is on
+    Fill,
+    /// only while fill is off
+    NotFill,
+    /// only if the last conditional step on the track played
+    Pre,
+    /// only if the last conditional step on the track didnt play
+    NotPre,
+}
+
+/// how conditions are written down: `1st`, `!1st`, `2:4`, `fill`, `!fill`, `pre`, `!pre`
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Always => write!(f, "always"),
+            Condition::First => write!(f, "1st"),
+            Condition::NotFirst => write!(f, "!1st"),
+            Condition::Every { nth, of } => write!(f, "{}:{}", nth, of),
+            Condition::Fill => write!(f, "fill"),
+            Condition::NotFill => write!(f, "!fill"),
+            Condition::Pre => write!(f, "pre"),
+            Condition::NotPre => write!(f, "!pre"),
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "always" => Condition::Always,
+            "1st" => Condition::First,
+            "!1st" => Condition::NotFirst,
+            "fill" => Condition::Fill,
+            "!fill" => Condition::NotFill,
+            "pre" => Condition::Pre,
+            "!pre" => Condition::NotPre,
+            _ => {
+                let (nth, of) = s.split_once(':').ok_or("unknown condition")?;
+                let nth: u8 = nth.parse().map_err(|_| "unknown condition")?;
+                let of: u8 = of.parse().map_err(|_| "unknown condition")?;
+                if nth == 0 || nth > of || !(2..=MAX_EVERY).contains(&of) {
+                    return Err("conditions go from 1:2 to 8:8");
+                }
+                Condition::Every { nth, of }
+            }
+        })
+    }
+}
+
+impl Condition {
+    /// every condition in the order the ui cycles through them
+    pub fn all() -> Vec<Condition> {
+        let mut all = vec![
+            Condition::Always,
+            Condition::First,
+            Condition::NotFirst,
+            Condition::Fill,
+            Condition::NotFill,
+            Condition::Pre,
+            Condition::NotPre,
+        ];
+        for of in 2..=MAX_EVERY {
+            for nth in 1..=of {
+                all.push(Condition::Every { nth, of });
+            }
+        }
+        all
+    }
+}
+
+/// A sound setting a step can lock to its own value
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Lock {
+    /// semitones up or down
+    Pitch,
+    /// dB louder or quieter
+    Gain,
+    /// -100 hard left to 100 hard right
+    Pan,
+    /// how far into the sample it starts, in percent
+    Start,
+}
+
+impl Lock {
+    /// every lock in the order the ui shows them
+    pub fn all() -> [Lock; 4] {
+        [Lock::Pitch, Lock::Gain, Lock::Pan, Lock::Start]
+    }
+
+    /// lowest and highest value it can be locked to
+    pub fn range(&self) -> (i8, i8) {
+        match self {
+            Lock::Pitch => (-24, 24),
+            Lock::Gain => (-60, 12),
+            Lock::Pan => (-100, 100),
+            Lock::Start => (0, 99),
+        }
+    }
+
+    /// how much one press in the ui changes it by
+    pub fn increment(&self) -> i8 {
+        match self {
+            Lock::Pitch | Lock::Gain => 1,
+            Lock::Pan | Lock::Start => 5,
+        }
+    }
+}
+
+/// how locks are written down: `pitch`, `gain`, `pan`, `start`
+impl fmt::Display for Lock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lock::Pitch => write!(f, "pitch"),
+            Lock::Gain => write!(f, "gain"),
+            Lock::Pan => write!(f, "pan"),
+            Lock::Start => write!(f, "start"),
+        }
+    }
+}
+
+impl FromStr for Lock {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Lock::all()
+            .into_iter()
+            .find(|l| l.to_string() == s)
+            .ok_or("unknown lock")
+    }
+}
+
+/// The sound settings a step has locked, None plays the sample as it is
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Locks {
+    pub pitch: Option<i8>,
+    pub gain: Option<i8>,
+    pub pan: Option<i8>,
+    pub start: Option<i8>,
+}
+
+impl Locks {
+    pub fn get(&self, lock: Lock) -> Option<i8> {
+        match lock {
+            Lock::Pitch => self.pitch,
+            Lock::Gain => self.gain,
+            Lock::Pan => self.pan,
+            Lock::Start => self.start,
+        }
+    }
+
+    /// lock a setting to a value, or unlock it with None
+    ///
+    /// returns an error if the value is out of the lock's range
+    pub fn set(&mut self, lock: Lock, value: Option<i8>) -> Result<(), &'static str> {
+        let (low, high) = lock.range();
+        if value.is_some_and(|v| v < low || v > high) {
+            return Err("lock value out of range");
+        }
+        match lock {
+            Lock::Pitch => self.pitch = value,
+            Lock::Gain => self.gain = value,
+            Lock::Pan => self.pan = value,
+            Lock::Start => self.start = value,
+        }
+        Ok(())
+    }
+
+    /// nothing is locked
+    pub fn is_empty(&self) -> bool {
+        *self == Locks::default()
+    }
+}
+
+/// Everything about one step of a track
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Step {
+    /// how hard it hits, 0 to `MAX_VELOCITY`. 0 is silent
+    pub velocity: u8,
+    /// percent chance it plays, 100 always does
+    pub probability: u8,
+    pub condition: Condition,
+    /// how many evenly spaced hits it plays inside the step, 1 is a normal hit
+    pub ratchet: u8,
+    /// each hit of a ratchet quieter than the last
+    pub decay: bool,
+    /// how early (negative) or late it plays, in `NUDGES_PER_STEP`ths of a step
+    pub nudge: i8,
+    pub locks: Locks,
+}
+
+impl Step {
+    /// the accent level its velocity is in
+    pub fn level(&self) -> AccentLevel {
+        AccentLevel::of(self.velocity)
+    }
+
+    /// does this step depend on something other than being there, ie: it has a chance of
+    /// not playing or a condition
+    pub fn is_conditional(&self) -> bool {
+        self.probability < 100 || self.condition != Condition::Always
+    }
+
+    /// how loud each hit of the ratchet is, as a fraction of the first one
+    pub fn ratchet_gains(&self) -> Vec<f64> {
+        let n = self.ratchet.max(1);
+        (0..n)
+            .map(|i| {
+                if self.decay {
+                    (n - i) as f64 / n as f64
+                } else {
+                    1.0
+                }
+            })
+            .collect()
+    }
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Step {
+            velocity: 0,
+            probability: 100,
+            condition: Condition::Always,
+            ratchet: 1,
+            decay: false,
+            nudge: 0,
+            locks: Locks::default(),
+        }
+    }
+}
+
+impl From<AccentLevel> for Step {
+    fn from(level: AccentLevel) -> Self {
+        Step {
+            velocity: level.velocity(),
+            ..Default::default()
+        }
+    }
+}
+
+/// steps are mostly looked at for how loud they are
+impl PartialEq<AccentLevel> for Step {
+    fn eq(&self, level: &AccentLevel) -> bool {
+        self.level() == *level
+    }
+}
 
-/// Stores information about when sounds get triggered
+/// A trck is a vec of steps
+pub type Track = Vec<Step>;
+
+/// Stores information about when sounds get triggered.
+/// Every track can have its own length, they each wrap around on their own
 #[derive(Default)]
 pub struct SampleSequence {
     tracks: Vec<Track>,
-    steps: usize,
 }
 
 impl SampleSequence {
-    /// new empty sequence
+    /// new empty sequence, every track the same length
     ///
     /// num tracks: how many tracks it got
     ///
@@ -27,12 +330,9 @@ impl SampleSequence {
         let mut tracks: Vec<Track> = vec![];
 
         for _ in 0..num_tracks {
-            tracks.push(vec![AccentLevel::Silent; num_steps]);
-        }
-        SampleSequence {
-            tracks,
-            steps: num_steps,
+            tracks.push(vec![Step::default(); num_steps]);
         }
+        SampleSequence { tracks }
     }
 }
 
@@ -49,7 +349,8 @@ pub trait Sequence {
     ///
     /// step: specifies the step, 0 indexed
     ///
-    /// trigger: sets a beat at an accent level for this step.
+    /// trigger: sets a beat to an accent level's velocity for this step. the rest of the
+    /// step is kept, unless its Silent which clears the whole step
     ///
     /// Returns an error if the track or step is out of bounds
     fn set_step(
@@ -59,12 +360,35 @@ pub trait Sequence {
         trigger: AccentLevel,
     ) -> Result<(), &'static str>;
 
+    /// returns everything about a step, None if the track or step is out of bounds
+    fn get_step(&self, track: usize, step: usize) -> Option<Step>;
+
+    /// Replace everything about a step
+    ///
+    /// Returns an error if the track or step is out of bounds
+    fn put_step(&mut self, track: usize, step: usize, step_data: Step) -> Result<(), &'static str>;
+
     /// returns the sequence
     fn get_sequence(&self) -> SampleSequence;
 
-    /// returns the number of steps this sequence has
+    /// returns the number of steps in the longest track, one time round the pattern
     fn steps(&self) -> usize;
 
+    /// returns the number of steps a track has, 0 if the track is out of bounds
+    fn track_steps(&self, track: usize) -> usize;
+
+    /// Change how many steps a track has. New steps are silent, steps past the
+    /// new end are dropped.
+    ///
+    /// Returns an error if the track is out of bounds or steps is 0
+    fn set_track_steps(&mut self, track: usize, steps: usize) -> Result<(), &'static str>;
+
+    /// Rearrange the tracks, eg: when their samples move around. New tracks are silent and
+    /// as long as the longest one, tracks that arent in from are dropped
+    ///
+    /// from: the track each one was before, None for new ones
+    fn remap_tracks(&mut self, from: &[Option<usize>]);
+
     /// returns a copy of the tracks in this sequence
     fn tracks(&self) -> Vec<Track>;
 
@@ -74,13 +398,13 @@ pub trait Sequence {
 impl Sequence for SampleSequence {
     fn clear_all(&mut self) {
         for i in 0..self.tracks.len() {
-            self.tracks[i] = vec![AccentLevel::Silent; self.steps];
+            self.clear_track(i);
         }
     }
 
     fn clear_track(&mut self, track: usize) {
         if track < self.tracks.len() {
-            self.tracks[track] = vec![AccentLevel::Silent; self.steps];
+            self.tracks[track] = vec![Step::default(); self.tracks[track].len()];
         }
     }
 
@@ -90,22 +414,66 @@ impl Sequence for SampleSequence {
         step: usize,
         trigger: AccentLevel,
     ) -> Result<(), &'static str> {
-        if track < self.tracks.len() && step < self.steps {
-            self.tracks[track][step] = trigger;
+        if track < self.tracks.len() && step < self.tracks[track].len() {
+            let s = &mut self.tracks[track][step];
+            if trigger == AccentLevel::Silent {
+                *s = Step::default();
+            } else {
+                s.velocity = trigger.velocity();
+            }
             return Ok(());
         }
         Err("track or step index out of bounds")
     }
 
+    fn get_step(&self, track: usize, step: usize) -> Option<Step> {
+        self.tracks.get(track)?.get(step).copied()
+    }
+
+    fn put_step(&mut self, track: usize, step: usize, step_data: Step) -> Result<(), &'static str> {
+        match self.tracks.get_mut(track).and_then(|t| t.get_mut(step)) {
+            Some(s) => {
+                *s = step_data;
+                Ok(())
+            }
+            None => Err("track or step index out of bounds"),
+        }
+    }
+
     fn get_sequence(&self) -> SampleSequence {
         SampleSequence {
             tracks: self.tracks.clone(),
-            steps: self.steps,
         }
     }
 
     fn steps(&self) -> usize {
-        self.steps
+        self.tracks.iter().map(|t| t.len()).max().unwrap_or(0)
+    }
+
+    fn track_steps(&self, track: usize) -> usize {
+        self.tracks.get(track).map_or(0, |t| t.len())
+    }
+
+    fn set_track_steps(&mut self, track: usize, steps: usize) -> Result<(), &'static str> {
+        if steps == 0 {
+            return Err("a track needs at least 1 step");
+        }
+        match self.tracks.get_mut(track) {
+            Some(t) => {
+                t.resize(steps, Step::default());
+                Ok(())
+            }
+            None => Err("track index out of bounds"),
+        }
+    }
+
+    fn remap_tracks(&mut self, from: &[Option<usize>]) {
+        let empty = vec![Step::default(); self.steps().max(1)];
+        self.tracks = from
+            .iter()
+            .map(|f| f.and_then(|t| self.tracks.get(t).cloned()))
+            .map(|t| t.unwrap_or(empty.clone()))
+            .collect();
     }
 
     fn tracks(&self) -> Vec<Track> {
@@ -117,10 +485,10 @@ impl Sequence for SampleSequence {
     }
 }
 
-#[allow(unused_must_use)]
+#[allow(unused_must_use, unused_assignments, clippy::len_zero)]
 #[cfg(test)]
 mod tests {
-    use super::{AccentLevel, SampleSequence, Sequence};
+    use super::{AccentLevel, Condition, Lock, SampleSequence, Sequence, Step};
 
     #[test]
     fn setting_steps() {
@@ -175,4 +543,91 @@ mod tests {
 
         assert_eq!(s.steps(), 5);
     }
+
+    #[test]
+    fn step_details() {
+        let mut s = SampleSequence::new(1, 4);
+        let step = Step {
+            velocity: AccentLevel::Soft.velocity(),
+            probability: 50,
+            condition: Condition::Every { nth: 1, of: 4 },
+            ..Default::default()
+        };
+        s.put_step(0, 2, step).unwrap();
+        assert_eq!(s.get_step(0, 2), Some(step));
+        assert!(s.get_step(0, 2).unwrap().is_conditional());
+        assert!(!s.get_step(0, 1).unwrap().is_conditional());
+
+        // changing the level keeps the rest, silencing it clears the lot
+        s.set_step(0, 2, AccentLevel::Loud);
+        assert_eq!(s.get_step(0, 2).unwrap().probability, 50);
+        s.set_step(0, 2, AccentLevel::Silent);
+        assert_eq!(s.get_step(0, 2), Some(Step::default()));
+
+        assert!(s.put_step(0, 4, step).is_err());
+
+        for c in Condition::all() {
+            assert_eq!(c.to_string().parse(), Ok(c));
+        }
+        assert!("0:4".parse::<Condition>().is_err());
+        assert!("5:4".parse::<Condition>().is_err());
+        assert!("1:9".parse::<Condition>().is_err());
+        assert!("sometimes".parse::<Condition>().is_err());
+        assert_eq!(s.get_step(1, 0), None);
+    }
+
+    #[test]
+    fn locks() {
+        let mut step = Step::default();
+        assert!(step.locks.is_empty());
+        step.locks.set(Lock::Pan, Some(-100)).unwrap();
+        assert_eq!(step.locks.get(Lock::Pan), Some(-100));
+        assert!(step.locks.set(Lock::Start, Some(100)).is_err());
+        assert!(step.locks.set(Lock::Gain, Some(13)).is_err());
+        step.locks.set(Lock::Pan, None).unwrap();
+        assert!(step.locks.is_empty());
+
+        for l in Lock::all() {
+            assert_eq!(l.to_string().parse(), Ok(l));
+        }
+        assert!("volume".parse::<Lock>().is_err());
+    }
+
+    #[test]
+    fn track_lengths() {
+        let mut s = SampleSequence::new(2, 16);
+        s.set_step(1, 10, AccentLevel::Loud);
+        s.set_step(1, 2, AccentLevel::Soft);
+        assert_eq!(s.set_track_steps(1, 12), Ok(()));
+        assert_eq!(s.track_steps(0), 16);
+        assert_eq!(s.track_steps(1), 12);
+        assert_eq!(s.steps(), 16);
+        assert!(s.set_step(1, 12, AccentLevel::Loud).is_err());
+        assert!(s.set_step(0, 12, AccentLevel::Loud).is_ok());
+
+        // growing back pads with silence, the old step 10 stays put
+        s.set_track_steps(1, 20);
+        assert_eq!(s.steps(), 20);
+        assert_eq!(s.tracks()[1][10], AccentLevel::Loud);
+        assert_eq!(s.tracks()[1][19], AccentLevel::Silent);
+
+        s.clear_all();
+        assert_eq!(s.track_steps(1), 20);
+        assert_eq!(s.tracks()[1][2], AccentLevel::Silent);
+
+        assert!(s.set_track_steps(1, 0).is_err());
+        assert!(s.set_track_steps(2, 4).is_err());
+        assert_eq!(s.track_steps(2), 0);
+
+        // new tracks are as long as the longest one, dropping tracks keeps the rest
+        s.remap_tracks(&[Some(0), Some(1), None]);
+        assert_eq!(s.track_steps(2), 20);
+        s.remap_tracks(&[Some(1), Some(0)]);
+        assert_eq!(s.num_tracks(), 2);
+        assert_eq!(s.track_steps(0), 20);
+        assert_eq!(s.track_steps(1), 16);
+        s.remap_tracks(&[Some(1)]);
+        assert_eq!(s.num_tracks(), 1);
+        assert_eq!(s.track_steps(0), 16);
+    }
 }
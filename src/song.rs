@@ -0,0 +1,199 @@
+/**
+Song mode, chaining patterns from the bank into an arrangement.
+
+A song is a list of entries, each plays a pattern some number of times round. When it
+gets to the end it either stops, or goes back round its loop: the whole song unless it
+has loop points.
+*/
+use crate::sequencer::{SampleSequence, Sequence};
+
+/// one pattern in a song, played a number of times in a row
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SongEntry {
+    /// slot in the pattern bank
+    pub pattern: usize,
+    /// how many times round, at least 1
+    pub repeats: u32,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Song {
+    pub entries: Vec<SongEntry>,
+    /// first and last entry the song loops round, the whole song if None
+    pub loop_points: Option<(usize, usize)>,
+    /// play through to the last entry once and stop, ignores the loop points
+    pub stop_at_end: bool,
+}
+
+impl Song {
+    /// the entry that plays after another one, None if the song stops there
+    pub fn next(&self, entry: usize) -> Option<usize> {
+        if self.stop_at_end {
+            return (entry + 1 < self.entries.len()).then_some(entry + 1);
+        }
+        let (first, last) = self.loop_range()?;
+        if entry == last || entry + 1 >= self.entries.len() {
+            Some(first)
+        } else {
+            Some(entry + 1)
+        }
+    }
+
+    /// first and last entry of the loop, None if the song is empty
+    pub fn loop_range(&self) -> Option<(usize, usize)> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        Some(self.loop_points.unwrap_or((0, self.entries.len() - 1)))
+    }
+
+    /// how many steps it takes to play from the first entry to the last one
+    ///
+    /// bank: the patterns the entries play
+    pub fn steps(&self, bank: &[SampleSequence]) -> usize {
+        self.entries
+            .iter()
+            .map(|e| bank.get(e.pattern).map_or(0, |p| p.steps()) * e.repeats as usize)
+            .sum()
+    }
+
+    /// put an entry in at an index, the loop points move along with the entries they're on
+    ///
+    /// returns an error if the index is past the end
+    pub fn insert(&mut self, at: usize, entry: SongEntry) -> Result<(), &'static str> {
+        if at > self.entries.len() {
+            return Err("song entry index out of bounds");
+        }
+        self.entries.insert(at, entry);
+        if let Some((first, last)) = self.loop_points {
+            let shift = |i: usize| if i >= at { i + 1 } else { i };
+            self.loop_points = Some((shift(first), shift(last)));
+        }
+        Ok(())
+    }
+
+    /// take an entry out, the loop shrinks with it and goes away if it was all thats in it
+    ///
+    /// returns an error if the index is out of bounds
+    pub fn remove(&mut self, at: usize) -> Result<(), &'static str> {
+        if at >= self.entries.len() {
+            return Err("song entry index out of bounds");
+        }
+        self.entries.remove(at);
+        if let Some((first, last)) = self.loop_points {
+            self.loop_points = if first == at && last == at {
+                None
+            } else {
+                let first = if first > at { first - 1 } else { first };
+                let last = if last >= at { last - 1 } else { last };
+                Some((first, last))
+            };
+        }
+        Ok(())
+    }
+
+    /// set where the loop starts, the end moves along if its before it
+    ///
+    /// returns an error if the entry is out of bounds
+    pub fn set_loop_start(&mut self, entry: usize) -> Result<(), &'static str> {
+        let (_, last) = self.loop_range().ok_or("song is empty")?;
+        if entry >= self.entries.len() {
+            return Err("song entry index out of bounds");
+        }
+        self.loop_points = Some((entry, last.max(entry)));
+        Ok(())
+    }
+
+    /// set where the loop ends, the start moves along if its after it
+    ///
+    /// returns an error if the entry is out of bounds
+    pub fn set_loop_end(&mut self, entry: usize) -> Result<(), &'static str> {
+        let (first, _) = self.loop_range().ok_or("song is empty")?;
+        if entry >= self.entries.len() {
+            return Err("song entry index out of bounds");
+        }
+        self.loop_points = Some((first.min(entry), entry));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Song, SongEntry};
+    use crate::sequencer::SampleSequence;
+
+    fn song(patterns: &[usize]) -> Song {
+        Song {
+            entries: patterns
+                .iter()
+                .map(|&pattern| SongEntry {
+                    pattern,
+                    repeats: 1,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn walking() {
+        let mut s = song(&[0, 1, 2, 3]);
+        assert_eq!(s.next(0), Some(1));
+        assert_eq!(s.next(3), Some(0));
+
+        s.loop_points = Some((1, 2));
+        assert_eq!(s.next(0), Some(1));
+        assert_eq!(s.next(2), Some(1));
+
+        s.stop_at_end = true;
+        assert_eq!(s.next(2), Some(3));
+        assert_eq!(s.next(3), None);
+
+        assert_eq!(Song::default().next(0), None);
+    }
+
+    #[test]
+    fn editing_keeps_the_loop() {
+        let mut s = song(&[0, 1, 2, 3]);
+        s.set_loop_start(1).unwrap();
+        assert_eq!(s.loop_points, Some((1, 3)));
+        s.set_loop_end(0).unwrap();
+        assert_eq!(s.loop_points, Some((0, 0)));
+        s.set_loop_end(2).unwrap();
+
+        s.insert(
+            0,
+            SongEntry {
+                pattern: 5,
+                repeats: 2,
+            },
+        )
+        .unwrap();
+        assert_eq!(s.loop_points, Some((1, 3)));
+        s.remove(2).unwrap();
+        assert_eq!(s.loop_points, Some((1, 2)));
+        s.remove(1).unwrap();
+        s.remove(1).unwrap();
+        assert_eq!(s.loop_points, None);
+
+        assert!(s.remove(9).is_err());
+        assert!(s
+            .insert(
+                9,
+                SongEntry {
+                    pattern: 0,
+                    repeats: 1
+                }
+            )
+            .is_err());
+        assert!(Song::default().set_loop_start(0).is_err());
+    }
+
+    #[test]
+    fn length() {
+        let bank = vec![SampleSequence::new(1, 4), SampleSequence::new(2, 3)];
+        let mut s = song(&[0, 1]);
+        s.entries[0].repeats = 3;
+        assert_eq!(s.steps(&bank), 15);
+    }
+}
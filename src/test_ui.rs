@@ -2,11 +2,19 @@
 #[cfg(not(pi))]
 extern crate pancurses;
 
-use crate::sequencer::{AccentLevel, SampleSequence, Sequence};
+use crate::effects::{Effects, Knob};
+use crate::mixer::ChannelStrip;
+use crate::playback::Playhead;
+use crate::sequencer::{AccentLevel, Lock, Locks, SampleSequence, Sequence};
+use crate::song::Song;
 use crate::ui::{Command, UIContent, Ui};
+use crate::velocity::AccentGains;
 use pancurses::{endwin, init_pair, initscr, start_color, Input};
 use pancurses::{COLOR_BLACK, COLOR_RED};
 
+/// how many columns the track labels get, longer sample names get cut
+static LABEL_WIDTH: usize = 10;
+
 pub struct Display {
     window: pancurses::Window,
 }
@@ -29,16 +37,154 @@ impl Display {
             .map(|track| {
                 track
                     .iter()
-                    .map(|&step| match step {
-                        AccentLevel::Loud => "#",
-                        AccentLevel::Regular => "+",
-                        AccentLevel::Soft => "-",
-                        AccentLevel::Silent => "_",
+                    .map(|step| match step.level() {
+                        AccentLevel::Silent => '_',
+                        // ratchets show how many hits they play
+                        _ if step.ratchet > 1 => char::from(b'0' + step.ratchet),
+                        AccentLevel::Loud => '#',
+                        AccentLevel::Regular => '+',
+                        AccentLevel::Soft => '-',
                     })
                     .collect()
             })
             .collect::<Vec<String>>()
     }
+
+    /// a step's locks on one line, `-` for the ones that arent locked. in step edit mode
+    /// the one being edited is in brackets
+    fn locks_format(locks: &Locks, editing: Option<Lock>) -> String {
+        let locks = Lock::all()
+            .iter()
+            .map(|&lock| {
+                let value = locks
+                    .get(lock)
+                    .map_or("-".to_string(), |v| format!("{:+}", v));
+                if editing == Some(lock) {
+                    format!("[{} {}]", lock, value)
+                } else {
+                    format!("{} {}", lock, value)
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+        format!(
+            "locks: {}{}",
+            locks,
+            if editing.is_some() { " EDIT" } else { "" }
+        )
+    }
+
+    /// the selected track's effect knobs then the buses', in effect edit mode the one being
+    /// turned is in brackets
+    fn effects_format(track: &Effects, buses: &Effects, editing: Option<Knob>) -> String {
+        let knobs = Knob::all()
+            .iter()
+            .map(|&knob| {
+                let value = if knob.is_bus() {
+                    buses.get(knob)
+                } else {
+                    track.get(knob)
+                };
+                let bus = if knob == Knob::ReverbSize { "| " } else { "" };
+                if editing == Some(knob) {
+                    format!("{}[{} {}]", bus, knob, value)
+                } else {
+                    format!("{}{} {}", bus, knob, value)
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+        format!(
+            "fx: {}{}",
+            knobs,
+            if editing.is_some() { " EDIT" } else { "" }
+        )
+    }
+
+    /// soft, regular and loud gains in dB: `-6/+0/+6`
+    fn accents_format(accents: &AccentGains) -> String {
+        format!(
+            "{:+}/{:+}/{:+}",
+            accents.soft, accents.regular, accents.loud
+        )
+    }
+
+    /// a track's channel strip, blank if its left alone: ` -3dB L20 M S`
+    fn strip_format(strip: &ChannelStrip) -> String {
+        let mut out = String::new();
+        if strip.volume != 0.0 {
+            out.push_str(&format!(" {:+}dB", strip.volume));
+        }
+        if strip.pan != 0 {
+            let side = if strip.pan < 0 { 'L' } else { 'R' };
+            out.push_str(&format!(" {}{}", side, strip.pan.unsigned_abs()));
+        }
+        if strip.mute {
+            out.push_str(" M");
+        }
+        if strip.solo {
+            out.push_str(" S");
+        }
+        out
+    }
+
+    /// record mode when its on: `REC q75% overdub`
+    fn record_format(record: bool, quantize: u8, overdub: bool) -> String {
+        if !record {
+            return String::new();
+        }
+        format!(
+            "REC q{}% {}",
+            quantize,
+            if overdub { "overdub" } else { "replace" }
+        )
+    }
+
+    /// where playback is: `playing 2 bar 1 step 5 loop 3`, counting from 1. with the steps
+    /// that got dropped for playback falling behind if there were any
+    fn playhead_format(playhead: Option<Playhead>) -> String {
+        let Some(at) = playhead else {
+            return String::new();
+        };
+        let mut out = format!(
+            "playing {} bar {} step {} loop {}",
+            at.slot + 1,
+            at.bar + 1,
+            at.step + 1,
+            at.loops + 1
+        );
+        if at.dropped > 0 {
+            out.push_str(&format!(" dropped {}", at.dropped));
+        }
+        out
+    }
+
+    /// the song on one line: `pattern x repeats` entries, `|` round the loop, `>` on the
+    /// one being edited
+    fn song_format(song: &Song, selected: usize) -> String {
+        let (first, last) = song.loop_range().unwrap_or((usize::MAX, usize::MAX));
+        song.entries
+            .iter()
+            .enumerate()
+            .map(|(i, e)| {
+                format!(
+                    "{}{}{}x{}{}",
+                    if i == first { "|" } else { "" },
+                    if i == selected { ">" } else { "" },
+                    e.pattern + 1,
+                    e.repeats,
+                    if i == last { "|" } else { "" },
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Ui for Display {
@@ -48,29 +194,141 @@ impl Ui for Display {
 
     /// updates the ui
     fn update(&self, content: UIContent) {
-        self.window.clear();
-        self.window.refresh();
-        self.window.printw(&format!(
-            "BPM: {} \t {} \n",
+        // it gets drawn on every step while playing, clearing the screen would flicker
+        self.window.erase();
+        self.window.printw(format!(
+            "BPM: {} \t swing: {}% \t accents: {}{} \t pattern: {} ({} cued) \t kit: {} \t ",
             content.tempo,
-            if content.muted { "M" } else { "" },
+            content.swing,
+            Display::accents_format(&content.accents),
+            if content.track_accent_edit {
+                " (track)"
+            } else {
+                ""
+            },
+            content.pattern + 1,
+            content.cued + 1,
+            content.kit,
+        ));
+        self.window.printw(format!(
+            "{}{} \t {}\n",
+            if content.muted { "M " } else { "" },
+            Display::record_format(content.record, content.quantize, content.overdub),
+            content.message,
+        ));
+        self.window.printw(format!(
+            "song {} {}: {} \t {}\n",
+            if content.song_mode { "on" } else { "off" },
+            if content.song.stop_at_end {
+                "stop"
+            } else {
+                "loop"
+            },
+            Display::song_format(content.song, content.song_entry),
+            Display::playhead_format(content.playhead),
+        ));
+        if let Some(step) = content.sequence.get_step(content.track, content.step) {
+            self.window.printw(format!(
+                "step: vel {} {}% {} x{}{} nudge {:+}/24 \t {}\n",
+                step.velocity,
+                step.probability,
+                step.condition,
+                step.ratchet,
+                if step.decay { " decay" } else { "" },
+                step.nudge,
+                if content.fill { "FILL" } else { "" },
+            ));
+            self.window.printw(format!(
+                "{}\n",
+                Display::locks_format(&step.locks, content.step_edit)
+            ));
+        }
+
+        self.window.printw(format!(
+            "{}\n",
+            Display::effects_format(
+                &content
+                    .effects
+                    .get(content.track)
+                    .copied()
+                    .unwrap_or_default(),
+                &content.bus_effects,
+                content.fx_edit,
+            )
         ));
 
+        // the playhead is only on the pattern being edited if its the one playing
+        let playhead = content.playhead.filter(|at| at.slot == content.pattern);
         let mut color: bool;
-        for track in Display::seq_format(content.sequence) {
+        for (t, track) in Display::seq_format(content.sequence).iter().enumerate() {
+            let name = content.samples.get(t).map_or("", |s| s.name.as_str());
+            self.window.attrset(pancurses::COLOR_PAIR(0));
+            self.window
+                .addstr(format!("{:<w$.w$} ", name, w = LABEL_WIDTH));
             color = true;
+            let len = track.chars().count() as u64;
+            let now = playhead.map(|at| (at.position % len.max(1)) as usize);
             for (i, step) in track.chars().enumerate() {
                 if i % content.divisions as usize == 0 {
                     self.window.attrset(pancurses::COLOR_PAIR(color as u32));
                     color = !color;
                 }
-                self.window.addstr(&format!("{step}"));
+                // steps that might not play are underlined
+                let conditional = content
+                    .sequence
+                    .get_step(t, i)
+                    .is_some_and(|s| s.is_conditional());
+                // steps that lock their sound are bold
+                let locked = content
+                    .sequence
+                    .get_step(t, i)
+                    .is_some_and(|s| !s.locks.is_empty());
+                if conditional {
+                    self.window.attron(pancurses::A_UNDERLINE);
+                }
+                if locked {
+                    self.window.attron(pancurses::A_BOLD);
+                }
+                // the step thats playing
+                if now == Some(i) {
+                    self.window.attron(pancurses::A_REVERSE);
+                }
+                self.window.addstr(format!("{step}"));
+                self.window
+                    .attroff(pancurses::A_UNDERLINE | pancurses::A_BOLD | pancurses::A_REVERSE);
+            }
+            if let Some(Some(swing)) = content.track_swing.get(t) {
+                self.window.attrset(pancurses::COLOR_PAIR(0));
+                self.window.addstr(format!(" ~{}%", swing));
+            }
+            if let Some(Some(accents)) = content.track_accents.get(t) {
+                self.window.attrset(pancurses::COLOR_PAIR(0));
+                self.window
+                    .addstr(format!(" a{}", Display::accents_format(accents)));
+            }
+            if let Some(strip) = content.mix.get(t) {
+                self.window.attrset(pancurses::COLOR_PAIR(0));
+                self.window.addstr(Display::strip_format(strip));
+            }
+            if let Some(Some(group)) = content.choke.get(t) {
+                self.window.attrset(pancurses::COLOR_PAIR(0));
+                self.window.addstr(format!(" k{}", group));
+            }
+            if content
+                .effects
+                .get(t)
+                .is_some_and(|fx| !fx.changed(false).is_empty())
+            {
+                self.window.attrset(pancurses::COLOR_PAIR(0));
+                self.window.addstr(" fx");
             }
             self.window.printw("\n");
         }
 
-        self.window
-            .mv(content.track as i32 + 1, content.step as i32);
+        self.window.mv(
+            content.track as i32 + 5,
+            (content.step + LABEL_WIDTH + 1) as i32,
+        );
         self.window.refresh();
     }
 
@@ -1,4 +1,10 @@
-use crate::sequencer::SampleSequence;
+use crate::effects::{Effects, Knob};
+use crate::mixer::ChannelStrip;
+use crate::playback::Playhead;
+use crate::samples::Sample;
+use crate::sequencer::{Lock, SampleSequence};
+use crate::song::Song;
+use crate::velocity::AccentGains;
 
 pub type Command = char;
 
@@ -8,7 +14,55 @@ pub struct UIContent<'a> {
     pub step: usize,
     pub track: usize,
     pub divisions: u32,
+    /// percent of a step every second step is late
+    pub swing: u32,
+    /// per track swing, None for the global one
+    pub track_swing: &'a [Option<u32>],
+    /// extra gain for each accent level
+    pub accents: AccentGains,
+    /// per track accent gains, None for the global ones
+    pub track_accents: &'a [Option<AccentGains>],
+    /// the accent keys change the selected track's gains
+    pub track_accent_edit: bool,
+    /// the kit the samples come out of
+    pub kit: &'a str,
+    /// the sample each track plays, their names label the tracks
+    pub samples: &'a [Sample],
+    /// every track's channel strip
+    pub mix: &'a [ChannelStrip],
+    /// every track's effects
+    pub effects: &'a [Effects],
+    /// the send buses' effects
+    pub bus_effects: Effects,
+    /// the knob being turned in effect edit mode, None outside of it
+    pub fx_edit: Option<Knob>,
+    /// every track's choke group, None for tracks that dont choke
+    pub choke: &'a [Option<u8>],
+    /// the pattern being edited
     pub sequence: &'a SampleSequence,
+    /// slot of the pattern being edited
+    pub pattern: usize,
+    /// slot of the pattern thats up to play
+    pub cued: usize,
+    pub song: &'a Song,
+    /// playing the song instead of the cued pattern
+    pub song_mode: bool,
+    /// the song entry being edited
+    pub song_entry: usize,
+    /// fill is on, for the fill trig conditions
+    pub fill: bool,
+    /// the lock being edited in step edit mode, None outside of it
+    pub step_edit: Option<Lock>,
+    /// the preview keys record into the playing pattern
+    pub record: bool,
+    /// percent of the way recorded hits get pulled onto their step
+    pub quantize: u8,
+    /// recording adds to whats there instead of replacing it
+    pub overdub: bool,
+    /// where playback is, None while its stopped
+    pub playhead: Option<Playhead>,
+    /// status line, eg: save and load errors
+    pub message: &'a str,
 }
 
 pub trait Ui {
@@ -0,0 +1,211 @@
+/**
+How loud a velocity is.
+
+The curve is a few `velocity:dB` points with straight lines between them, in dB. Below the
+first point it keeps going down the same line, above the last one it stays put. The default
+goes through the accent presets at the gains the soft, regular and loud hits always had, so
+patterns from before velocities sound the same.
+
+Accent gains go on top of the curve, one for every accent level a velocity can fall in.
+They're 0dB unless they get turned up or down, for the whole beat or just one track.
+*/
+use std::fmt;
+use std::str::FromStr;
+
+use crate::sequencer::{AccentLevel, MAX_VELOCITY};
+
+/// Maps velocity to gain in dB
+#[derive(Clone, PartialEq, Debug)]
+pub struct VelocityCurve {
+    /// (velocity, dB), in order of velocity, at least 2 of them
+    points: Vec<(u8, f64)>,
+}
+
+impl VelocityCurve {
+    /// a curve through some points
+    ///
+    /// returns an error if theres less than 2 points or they arent in order of velocity
+    pub fn new(points: Vec<(u8, f64)>) -> Result<VelocityCurve, &'static str> {
+        if points.len() < 2 {
+            return Err("a velocity curve needs at least 2 points");
+        }
+        if points.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return Err("velocity curve points go from low to high velocity");
+        }
+        Ok(VelocityCurve { points })
+    }
+
+    /// gain in dB a velocity plays at, silent is -inf
+    pub fn gain_db(&self, velocity: u8) -> f64 {
+        if velocity == 0 {
+            return f64::NEG_INFINITY;
+        }
+        let segment = self
+            .points
+            .windows(2)
+            .find(|w| velocity <= w[1].0)
+            .unwrap_or(&self.points[self.points.len() - 2..]);
+        let ((v0, db0), (v1, db1)) = (segment[0], segment[1]);
+        if velocity > v1 {
+            return db1;
+        }
+        let at = (velocity as f64 - v0 as f64) / (v1 as f64 - v0 as f64);
+        db0 + (db1 - db0) * at
+    }
+}
+
+impl Default for VelocityCurve {
+    fn default() -> Self {
+        VelocityCurve {
+            points: vec![
+                (1, -30.0),
+                (AccentLevel::Soft.velocity(), -6.0),
+                (AccentLevel::Regular.velocity(), 0.0),
+                (MAX_VELOCITY, 6.0),
+            ],
+        }
+    }
+}
+
+/// how curves are written down: `1:-30 48:-6 96:0 127:6`
+impl fmt::Display for VelocityCurve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let points: Vec<String> = self
+            .points
+            .iter()
+            .map(|(v, db)| format!("{}:{}", v, db))
+            .collect();
+        write!(f, "{}", points.join(" "))
+    }
+}
+
+impl FromStr for VelocityCurve {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let points = s
+            .split_whitespace()
+            .map(|point| {
+                let (v, db) = point
+                    .split_once(':')
+                    .ok_or("curve points are velocity:dB")?;
+                let v: u8 = v.parse().map_err(|_| "curve points are velocity:dB")?;
+                let db: f64 = db.parse().map_err(|_| "curve points are velocity:dB")?;
+                if v == 0 || v > MAX_VELOCITY || !db.is_finite() {
+                    return Err("curve velocities go from 1 to 127");
+                }
+                Ok((v, db))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        VelocityCurve::new(points)
+    }
+}
+
+/// the quietest and loudest an accent gain goes, in dB
+pub const ACCENT_RANGE: (f64, f64) = (-60.0, 12.0);
+
+/// Extra gain in dB for the hits in each accent level
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct AccentGains {
+    pub soft: f64,
+    pub regular: f64,
+    pub loud: f64,
+}
+
+impl AccentGains {
+    /// the gain for a level, silent is -inf
+    pub fn get(&self, level: AccentLevel) -> f64 {
+        match level {
+            AccentLevel::Silent => f64::NEG_INFINITY,
+            AccentLevel::Soft => self.soft,
+            AccentLevel::Regular => self.regular,
+            AccentLevel::Loud => self.loud,
+        }
+    }
+
+    /// turn a level up or down, it stays inside `ACCENT_RANGE`. silent stays silent
+    pub fn adjust(&mut self, level: AccentLevel, db: f64) {
+        let gain = match level {
+            AccentLevel::Silent => return,
+            AccentLevel::Soft => &mut self.soft,
+            AccentLevel::Regular => &mut self.regular,
+            AccentLevel::Loud => &mut self.loud,
+        };
+        *gain = (*gain + db).clamp(ACCENT_RANGE.0, ACCENT_RANGE.1);
+    }
+}
+
+/// how accent gains are written down: `soft,regular,loud`, eg: `-3,0,2`
+impl fmt::Display for AccentGains {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{}", self.soft, self.regular, self.loud)
+    }
+}
+
+impl FromStr for AccentGains {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let gains = s
+            .split(',')
+            .map(|g| match g.trim().parse::<f64>() {
+                Ok(g) if (ACCENT_RANGE.0..=ACCENT_RANGE.1).contains(&g) => Ok(g),
+                _ => Err("accent gains are soft,regular,loud in dB, -60 to 12"),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match gains[..] {
+            [soft, regular, loud] => Ok(AccentGains {
+                soft,
+                regular,
+                loud,
+            }),
+            _ => Err("accent gains are soft,regular,loud in dB, -60 to 12"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccentGains, VelocityCurve};
+    use crate::sequencer::AccentLevel;
+
+    #[test]
+    fn default_keeps_the_old_gains() {
+        let c = VelocityCurve::default();
+        assert_eq!(c.gain_db(AccentLevel::Soft.velocity()), -6.0);
+        assert_eq!(c.gain_db(AccentLevel::Regular.velocity()), 0.0);
+        assert_eq!(c.gain_db(AccentLevel::Loud.velocity()), 6.0);
+        assert_eq!(c.gain_db(72), -3.0);
+        assert_eq!(c.gain_db(0), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn curves() {
+        let c: VelocityCurve = "10:-20 100:-2".parse().unwrap();
+        assert_eq!(c.gain_db(55), -11.0);
+        assert!((c.gain_db(1) + 21.8).abs() < 1e-9);
+        assert_eq!(c.gain_db(127), -2.0);
+        assert_eq!(c.to_string().parse(), Ok(c));
+
+        assert!("10:-20".parse::<VelocityCurve>().is_err());
+        assert!("100:-2 10:-20".parse::<VelocityCurve>().is_err());
+        assert!("0:-20 100:0".parse::<VelocityCurve>().is_err());
+        assert!("10:loud 100:0".parse::<VelocityCurve>().is_err());
+    }
+
+    #[test]
+    fn accent_gains() {
+        let mut g = AccentGains::default();
+        g.adjust(AccentLevel::Loud, 3.0);
+        g.adjust(AccentLevel::Soft, -100.0);
+        g.adjust(AccentLevel::Silent, 3.0);
+        assert_eq!(g.get(AccentLevel::Loud), 3.0);
+        assert_eq!(g.get(AccentLevel::Soft), -60.0);
+        assert_eq!(g.get(AccentLevel::Silent), f64::NEG_INFINITY);
+        assert_eq!(g.to_string().parse(), Ok(g));
+
+        assert!("0,0".parse::<AccentGains>().is_err());
+        assert!("0,0,13".parse::<AccentGains>().is_err());
+        assert!("0,loud,0".parse::<AccentGains>().is_err());
+    }
+}